@@ -0,0 +1,247 @@
+//! Chat load generator. Registers `--clients` text-protocol users, spreads
+//! them over `--channels` channels and has every client send `--messages`
+//! chat messages at once. Reports delivery throughput and end-to-end
+//! latency.
+//!
+//! Every run registers new accounts, since all clients share one address
+//! and failed logins from it are throttled. Raise the `[flood]` limits of
+//! the server under test first, otherwise most messages are rejected as
+//! flooding.
+
+use clap::Parser;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::sync::{Barrier, Semaphore};
+use tokio::time;
+
+#[derive(Debug, Parser)]
+#[command(name = "loadtest", about = "Whats Up Rust 2 load generator")]
+struct Args {
+    /// Address of the server's plain TCP listener.
+    #[arg(long, default_value = "127.0.0.1:6969")]
+    addr: String,
+    #[arg(long, default_value_t = 200)]
+    clients: usize,
+    #[arg(long, default_value_t = 20)]
+    channels: usize,
+    /// Chat messages sent by each client.
+    #[arg(long, default_value_t = 50)]
+    messages: usize,
+    /// Logins in flight at once; password hashing is slow.
+    #[arg(long, default_value_t = 16)]
+    login_concurrency: usize,
+    #[arg(long, default_value = "loadtest-password")]
+    password: String,
+    /// Username prefix, unique per run by default.
+    #[arg(long)]
+    prefix: Option<String>,
+    /// Seconds to wait for all deliveries before giving up.
+    #[arg(long, default_value_t = 120)]
+    timeout: u64,
+}
+
+struct Client {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Client {
+    async fn line(&mut self) -> std::io::Result<String> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(line.trim_end().to_string())
+    }
+
+    async fn send(&mut self, line: &str) -> std::io::Result<()> {
+        self.writer.write_all(format!("{line}\n").as_bytes()).await
+    }
+
+    /// Reads lines until one starts with one of `prefixes` and returns it.
+    async fn expect(&mut self, prefixes: &[&str]) -> std::io::Result<String> {
+        loop {
+            let line = self.line().await?;
+            if prefixes.iter().any(|prefix| line.starts_with(prefix)) {
+                return Ok(line);
+            }
+        }
+    }
+
+    async fn connect(args: &Args, username: &str, channel: &str) -> std::io::Result<Self> {
+        let stream = TcpStream::connect(&args.addr).await?;
+        let (reader, writer) = stream.into_split();
+        let mut client = Client {
+            reader: BufReader::new(reader),
+            writer,
+        };
+
+        client
+            .send(&format!("REGISTER {username} {}", args.password))
+            .await?;
+        let reply = client.expect(&["AUTH OK", "AUTH ERR"]).await?;
+        if reply.starts_with("AUTH ERR") {
+            return Err(std::io::Error::other(format!("{username}: {reply}")));
+        }
+
+        client.send(&format!("/create {channel}")).await?;
+        client.send(&format!("/join {channel}")).await?;
+        client.expect(&["Switched from"]).await?;
+        Ok(client)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    received: usize,
+    latencies: Vec<Duration>,
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    let args = Arc::new(Args::parse());
+    let logins = Arc::new(Semaphore::new(args.login_concurrency));
+    // Everyone is logged in and joined before the first message goes out.
+    let ready = Arc::new(Barrier::new(args.clients + 1));
+    let epoch = Instant::now();
+    let prefix = args.prefix.clone().unwrap_or_else(|| {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        format!("lt{}", now.as_secs() % 1_000_000)
+    });
+
+    let mut per_channel = vec![0usize; args.channels];
+    for i in 0..args.clients {
+        per_channel[i % args.channels] += 1;
+    }
+
+    println!(
+        "logging in {} clients across {} channels...",
+        args.clients, args.channels
+    );
+    let mut tasks = Vec::new();
+    for i in 0..args.clients {
+        let args = args.clone();
+        let logins = logins.clone();
+        let ready = ready.clone();
+        let channel_index = i % args.channels;
+        let expected = (per_channel[channel_index] - 1) * args.messages;
+        let username = format!("{prefix}_{i:04}");
+        tasks.push(tokio::spawn(async move {
+            let channel = format!("bench-{channel_index}");
+            let client = {
+                let _permit = logins.acquire().await.expect("semaphore closed");
+                Client::connect(&args, &username, &channel).await
+            };
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    ready.wait().await;
+                    ready.wait().await;
+                    return Err(e);
+                }
+            };
+            ready.wait().await;
+            // Wait for the other clients' backlog to settle.
+            ready.wait().await;
+
+            let Client { reader, mut writer } = client;
+            let messages = args.messages;
+            let sender = tokio::spawn(async move {
+                for seq in 0..messages {
+                    let sent = epoch.elapsed().as_micros();
+                    let line = format!("bench {seq} {sent}\n");
+                    writer.write_all(line.as_bytes()).await?;
+                }
+                Ok::<_, std::io::Error>(writer)
+            });
+
+            let mut reader = reader;
+            let mut report = Report::default();
+            let deadline = time::Instant::now() + Duration::from_secs(args.timeout);
+            let mut line = String::new();
+            while report.received < expected {
+                line.clear();
+                match time::timeout_at(deadline, reader.read_line(&mut line)).await {
+                    Ok(Ok(0)) | Err(_) => break,
+                    Ok(Ok(_)) => {}
+                    Ok(Err(e)) => return Err(e),
+                }
+                // Live chat only; history replayed on join starts with a date.
+                if !line.starts_with("[bench-") {
+                    continue;
+                }
+                let Some(sent) = line
+                    .split_once(": bench ")
+                    .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+                    .and_then(|sent| sent.parse::<u64>().ok())
+                else {
+                    continue;
+                };
+                let now = epoch.elapsed().as_micros() as u64;
+                report.received += 1;
+                report
+                    .latencies
+                    .push(Duration::from_micros(now.saturating_sub(sent)));
+            }
+            let _writer = sender.await.map_err(std::io::Error::other)??;
+            Ok(report)
+        }));
+    }
+
+    ready.wait().await;
+    let login_time = epoch.elapsed();
+    time::sleep(Duration::from_secs(1)).await;
+    println!("logged in after {login_time:.1?}, sending...");
+    let start = Instant::now();
+    ready.wait().await;
+
+    let mut received = 0;
+    let mut failed = 0;
+    let mut latencies = Vec::new();
+    for task in tasks {
+        match task.await.map_err(std::io::Error::other)? {
+            Ok(report) => {
+                received += report.received;
+                latencies.extend(report.latencies);
+            }
+            Err(e) => {
+                eprintln!("client failed: {e}");
+                failed += 1;
+            }
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let expected: usize = per_channel
+        .iter()
+        .map(|members| members * (members - 1) * args.messages)
+        .sum();
+    latencies.sort();
+    let percentile = |p: f64| {
+        latencies
+            .get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+
+    println!("sent:       {}", args.clients * args.messages);
+    println!("delivered:  {received} of {expected}");
+    println!("failed:     {failed} clients");
+    println!("elapsed:    {elapsed:.2?}");
+    println!(
+        "throughput: {:.0} deliveries/s",
+        received as f64 / elapsed.as_secs_f64()
+    );
+    println!(
+        "latency:    p50 {:.1?}  p99 {:.1?}  max {:.1?}",
+        percentile(0.50),
+        percentile(0.99),
+        latencies.last().copied().unwrap_or_default()
+    );
+    Ok(())
+}
@@ -0,0 +1,188 @@
+//! Compares the presence registry with the single `Mutex<HashMap>` it
+//! replaced, without sockets or a database. Every simulated user sends
+//! `--messages` messages to their channel and now and then switches
+//! channels, all at once.
+
+use clap::Parser;
+use std::collections::HashMap;
+use std::hint::black_box;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+#[allow(dead_code)]
+#[path = "../src/presence.rs"]
+mod presence;
+
+use presence::{Member, Presence};
+
+#[derive(Debug, Parser)]
+#[command(name = "presence_bench", about = "Presence registry benchmark")]
+struct Args {
+    #[arg(long, default_value_t = 2000)]
+    users: usize,
+    #[arg(long, default_value_t = 100)]
+    channels: usize,
+    /// Messages sent by each user.
+    #[arg(long, default_value_t = 20)]
+    messages: usize,
+    /// Every n-th action is a channel switch instead of a message.
+    #[arg(long, default_value_t = 10)]
+    switch_every: usize,
+}
+
+#[derive(Clone)]
+struct Session {
+    name: String,
+    channel: String,
+    inbox: Arc<AtomicU64>,
+}
+
+impl Session {
+    /// Stands in for queueing an event: one allocation per recipient.
+    fn deliver(&self, channel: &str, sender: &str, text: &str) {
+        black_box(format!("[{channel}] {sender}: {text}"));
+        self.inbox.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+impl Member for Session {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    fn set_channel(&mut self, channel: String) {
+        self.channel = channel;
+    }
+
+    fn is_same_session(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.inbox, &other.inbox)
+    }
+}
+
+trait Registry: Clone + Send + Sync + 'static {
+    fn broadcast(&self, sender: &str, text: &str) -> impl Future<Output = ()> + Send;
+    fn switch(&self, name: &str, channel: String) -> impl Future<Output = ()> + Send;
+}
+
+/// The old layout: one lock, held while scanning every user.
+type Global = Arc<Mutex<HashMap<String, Session>>>;
+
+impl Registry for Global {
+    async fn broadcast(&self, sender: &str, text: &str) {
+        let channel = {
+            let users = self.lock().await;
+            users.get(sender).map(|user| user.channel.clone())
+        };
+        let Some(channel) = channel else { return };
+        let users = self.lock().await;
+        for (name, user) in users.iter() {
+            if name != sender && user.channel == channel {
+                user.deliver(&channel, sender, text);
+            }
+        }
+    }
+
+    async fn switch(&self, name: &str, channel: String) {
+        if let Some(user) = self.lock().await.get_mut(name) {
+            user.channel = channel;
+        }
+    }
+}
+
+impl Registry for Presence<Session> {
+    async fn broadcast(&self, sender: &str, text: &str) {
+        let Some(channel) = self.channel_of(sender) else {
+            return;
+        };
+        for user in self.members(&channel).iter() {
+            if user.name != sender {
+                user.deliver(&channel, sender, text);
+            }
+        }
+    }
+
+    async fn switch(&self, name: &str, channel: String) {
+        self.move_to(name, channel);
+    }
+}
+
+fn sessions(args: &Args) -> Vec<Session> {
+    (0..args.users)
+        .map(|i| Session {
+            name: format!("user{i}"),
+            channel: format!("channel{}", i % args.channels),
+            inbox: Arc::default(),
+        })
+        .collect()
+}
+
+async fn run<R: Registry>(registry: R, sessions: &[Session], args: &Args) -> (Duration, u64) {
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (i, session) in sessions.iter().enumerate() {
+        let registry = registry.clone();
+        let name = session.name.clone();
+        let (messages, channels, switch_every) = (args.messages, args.channels, args.switch_every);
+        tasks.push(tokio::spawn(async move {
+            let mut sent = 0;
+            let mut action = 0;
+            while sent < messages {
+                action += 1;
+                if switch_every > 0 && action % switch_every == 0 {
+                    let channel = format!("channel{}", (i + action) % channels);
+                    registry.switch(&name, channel).await;
+                } else {
+                    registry.broadcast(&name, "hello").await;
+                    sent += 1;
+                }
+                tokio::task::yield_now().await;
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.expect("bench task panicked");
+    }
+    let delivered = sessions
+        .iter()
+        .map(|session| session.inbox.load(Ordering::Relaxed))
+        .sum();
+    (start.elapsed(), delivered)
+}
+
+fn report(label: &str, (elapsed, delivered): (Duration, u64)) {
+    println!(
+        "{label:<9} {elapsed:>10.2?}  {delivered:>10} deliveries  {:>12.0} deliveries/s",
+        delivered as f64 / elapsed.as_secs_f64()
+    );
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    println!(
+        "{} users, {} channels, {} messages each, switching every {} actions",
+        args.users, args.channels, args.messages, args.switch_every
+    );
+
+    let global_sessions = sessions(&args);
+    let global: Global = Arc::new(Mutex::new(
+        global_sessions
+            .iter()
+            .map(|session| (session.name.clone(), session.clone()))
+            .collect(),
+    ));
+    report("mutex", run(global, &global_sessions, &args).await);
+
+    let presence_sessions = sessions(&args);
+    let registry = Presence::new();
+    for session in &presence_sessions {
+        registry.insert(session.clone());
+    }
+    report("presence", run(registry, &presence_sessions, &args).await);
+}
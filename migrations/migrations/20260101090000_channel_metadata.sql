@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE channels
+    ADD COLUMN topic TEXT,
+    ADD COLUMN created_by BIGINT REFERENCES users(id);
+
+INSERT INTO channels (name)
+VALUES ('Global')
+ON CONFLICT (name) DO NOTHING;
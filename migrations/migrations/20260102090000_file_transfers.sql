@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN recipient_id BIGINT REFERENCES users(id),
+    ADD COLUMN file_size BIGINT,
+    ADD COLUMN file_sha256 TEXT;
+
+CREATE INDEX idx_messages_recipient_time
+ON messages(recipient_id, created_at);
@@ -0,0 +1,9 @@
+-- Add migration script here
+CREATE TYPE user_role AS ENUM ('user', 'mod', 'admin', 'owner');
+
+ALTER TABLE users
+    ADD COLUMN role user_role NOT NULL DEFAULT 'user';
+
+UPDATE users
+SET role = 'owner'
+WHERE id = (SELECT id FROM users ORDER BY created_at, id LIMIT 1);
@@ -0,0 +1,21 @@
+-- Add migration script here
+CREATE TYPE sanction_kind AS ENUM ('ban', 'mute');
+
+CREATE TABLE channel_operators (
+    channel_id BIGINT REFERENCES channels(id) ON DELETE CASCADE NOT NULL,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    granted_by BIGINT REFERENCES users(id),
+    created_at TIMESTAMPTZ DEFAULT now(),
+    PRIMARY KEY (channel_id, user_id)
+);
+
+CREATE TABLE channel_sanctions (
+    channel_id BIGINT REFERENCES channels(id) ON DELETE CASCADE NOT NULL,
+    user_id BIGINT REFERENCES users(id) ON DELETE CASCADE NOT NULL,
+    kind sanction_kind NOT NULL,
+    reason TEXT,
+    created_by BIGINT REFERENCES users(id),
+    expires_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ DEFAULT now(),
+    PRIMARY KEY (channel_id, user_id, kind)
+);
@@ -0,0 +1,11 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN delivered_at TIMESTAMPTZ;
+
+UPDATE messages
+SET delivered_at = created_at
+WHERE recipient_id IS NOT NULL;
+
+CREATE INDEX idx_messages_undelivered
+ON messages(recipient_id)
+WHERE delivered_at IS NULL;
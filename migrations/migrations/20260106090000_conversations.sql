@@ -0,0 +1,28 @@
+-- Add migration script here
+CREATE TABLE conversations (
+    id BIGSERIAL PRIMARY KEY,
+    user_low BIGINT REFERENCES users(id) NOT NULL,
+    user_high BIGINT REFERENCES users(id) NOT NULL,
+    created_at TIMESTAMPTZ DEFAULT now(),
+    UNIQUE (user_low, user_high),
+    CHECK (user_low <= user_high)
+);
+
+ALTER TABLE messages
+    ADD COLUMN conversation_id BIGINT REFERENCES conversations(id);
+
+INSERT INTO conversations (user_low, user_high)
+SELECT DISTINCT LEAST(sender_id, recipient_id), GREATEST(sender_id, recipient_id)
+FROM messages
+WHERE recipient_id IS NOT NULL AND file_url IS NULL
+ON CONFLICT (user_low, user_high) DO NOTHING;
+
+UPDATE messages m
+SET conversation_id = c.id
+FROM conversations c
+WHERE m.recipient_id IS NOT NULL AND m.file_url IS NULL
+  AND c.user_low = LEAST(m.sender_id, m.recipient_id)
+  AND c.user_high = GREATEST(m.sender_id, m.recipient_id);
+
+CREATE INDEX idx_messages_conversation
+ON messages(conversation_id, id);
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE messages
+    ADD COLUMN search_vector TSVECTOR
+    GENERATED ALWAYS AS (to_tsvector('english', COALESCE(text, ''))) STORED;
+
+CREATE INDEX idx_messages_search
+ON messages USING GIN (search_vector);
@@ -2,39 +2,199 @@ use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
 };
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Error, ErrorKind, Result};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use chrono::Utc;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{Error, ErrorKind, Result};
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
 
+use crate::connection::{self, FrameReader, FrameWriter};
 use crate::db::UserDb;
+use crate::protocol::{Event, HELLO, Mode};
+use crate::telemetry;
+use crate::validation::{self, Login, Registration};
+
+/// Failed attempts after which a connection is dropped.
+const MAX_CONNECTION_FAILURES: u32 = 5;
+
+/// Failed logins after which an account is locked, first for
+/// `LOCKOUT_BASE_SECS`, doubling with each further failure.
+const LOCKOUT_THRESHOLD: i32 = 5;
+const LOCKOUT_BASE_SECS: f64 = 30.0;
+const LOCKOUT_MAX_SECS: f64 = 3600.0;
+
+/// Failures an address gets for free before it has to back off, starting
+/// at `IP_BASE_DELAY` and doubling with each further failure.
+const IP_FREE_FAILURES: u32 = 5;
+const IP_BASE_DELAY: Duration = Duration::from_secs(1);
+const IP_MAX_DELAY: Duration = Duration::from_secs(300);
+/// An address with no failures for this long starts over.
+const IP_FORGET_AFTER: Duration = Duration::from_secs(900);
+const IP_PRUNE_AT: usize = 1024;
 
 pub struct Auth {
     user_db: UserDb,
+    peer: IpAddr,
+    limiter: LoginLimiter,
+    failures: u32,
+}
+
+/// Failed authentication attempts per client address, shared by all
+/// connections.
+#[derive(Debug, Clone, Default)]
+pub struct LoginLimiter {
+    addresses: Arc<Mutex<HashMap<IpAddr, AddressFailures>>>,
+}
+
+#[derive(Debug)]
+struct AddressFailures {
+    count: u32,
+    last: Instant,
+    blocked_until: Option<Instant>,
+}
+
+impl LoginLimiter {
+    async fn allows(&self, ip: IpAddr) -> bool {
+        let addresses = self.addresses.lock().await;
+        addresses
+            .get(&ip)
+            .and_then(|failures| failures.blocked_until)
+            .is_none_or(|until| until <= Instant::now())
+    }
+
+    async fn record_failure(&self, ip: IpAddr) {
+        let now = Instant::now();
+        let mut addresses = self.addresses.lock().await;
+        if addresses.len() >= IP_PRUNE_AT {
+            addresses.retain(|_, failures| now.duration_since(failures.last) < IP_FORGET_AFTER);
+        }
+
+        let failures = addresses.entry(ip).or_insert(AddressFailures {
+            count: 0,
+            last: now,
+            blocked_until: None,
+        });
+        if now.duration_since(failures.last) >= IP_FORGET_AFTER {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+        if failures.count > IP_FREE_FAILURES {
+            let doublings = (failures.count - IP_FREE_FAILURES - 1).min(16);
+            let delay = (IP_BASE_DELAY * 2u32.pow(doublings)).min(IP_MAX_DELAY);
+            failures.blocked_until = Some(now + delay);
+            warn!(%ip, failures = failures.count, ?delay, "address backing off");
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthFailure {
+    BadRequest,
+    /// The credentials break a validation rule, described by the message.
+    Invalid(String),
+    UnknownUser,
+    WrongPassword,
+    InvalidCredentials,
+    UsernameTaken,
+    TooManyAttempts,
+    Unavailable,
+}
+
+impl AuthFailure {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthFailure::BadRequest => "bad_request",
+            AuthFailure::Invalid(_) => "invalid_input",
+            AuthFailure::UnknownUser => "unknown_user",
+            AuthFailure::WrongPassword => "wrong_password",
+            AuthFailure::InvalidCredentials => "invalid_credentials",
+            AuthFailure::UsernameTaken => "username_taken",
+            AuthFailure::TooManyAttempts => "rate_limited",
+            AuthFailure::Unavailable => "unavailable",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AuthFailure::BadRequest => "Expected LOGIN <user> <pass> or REGISTER <user> <pass>",
+            AuthFailure::Invalid(message) => message,
+            AuthFailure::UnknownUser => "User doesn't exist",
+            AuthFailure::WrongPassword => "Wrong password",
+            AuthFailure::InvalidCredentials => "Invalid username or password",
+            AuthFailure::UsernameTaken => "Username is already taken",
+            AuthFailure::TooManyAttempts => "Too many failed attempts, try again later",
+            AuthFailure::Unavailable => "Authentication is unavailable, try again later",
+        }
+    }
+}
+
+enum Handshake<'a> {
+    Login {
+        username: &'a str,
+        password: &'a str,
+    },
+    Register {
+        username: &'a str,
+        password: &'a str,
+    },
+}
+
+impl<'a> Handshake<'a> {
+    fn is_handshake(input: &str) -> bool {
+        matches!(input.split(' ').next(), Some("LOGIN" | "REGISTER"))
+    }
+
+    fn parse(input: &'a str) -> Option<Self> {
+        let parts: Vec<&str> = input.splitn(3, ' ').collect();
+        match parts.as_slice() {
+            ["LOGIN", username, password] if !username.is_empty() && !password.is_empty() => {
+                Some(Handshake::Login { username, password })
+            }
+            ["REGISTER", username, password] if !username.is_empty() && !password.is_empty() => {
+                Some(Handshake::Register { username, password })
+            }
+            _ => None,
+        }
+    }
 }
 
 impl Auth {
-    pub fn new(user_db: UserDb) -> Self {
-        Self { user_db }
+    pub fn new(user_db: UserDb, peer: IpAddr, limiter: LoginLimiter) -> Self {
+        Self {
+            user_db,
+            peer,
+            limiter,
+            failures: 0,
+        }
     }
 
     pub async fn auth(
-        &self,
-        writer: &mut OwnedWriteHalf,
-        reader: &mut OwnedReadHalf,
+        &mut self,
+        writer: &mut FrameWriter,
+        reader: &mut FrameReader,
     ) -> Result<String> {
         loop {
-            let mut answer = String::new();
-            writer
-                .write_all(b"Do you have an account? (y/n) \n")
-                .await?;
-            let mut reader = BufReader::new(&mut *reader);
-            reader.read_line(&mut answer).await?;
-            let answer = answer.trim();
-            match answer {
+            let answer = Self::read_input(writer, reader, "Do you have an account? (y/n) ").await?;
+            match answer.as_str() {
                 "y" => {
-                    return self.login(writer, &mut reader).await;
+                    return self.login(writer, reader).await;
                 }
                 "n" => {
-                    return self.register(writer, &mut reader).await;
+                    return self.register(writer, reader).await;
+                }
+                HELLO if reader.mode() == Mode::Text => {
+                    connection::upgrade(reader, writer).await?;
+                    continue;
+                }
+                _ if Handshake::is_handshake(&answer) => {
+                    if let Some(username) = self.handshake(writer, &answer).await? {
+                        return Ok(username);
+                    }
+                    continue;
                 }
                 _ => {
                     continue;
@@ -43,10 +203,58 @@ impl Auth {
         }
     }
 
+    /// Handles a single-line `LOGIN <user> <pass>` or `REGISTER <user> <pass>`
+    /// request and answers with an `auth_result` event instead of prose.
+    async fn handshake(&mut self, writer: &mut FrameWriter, input: &str) -> Result<Option<String>> {
+        let result = match Handshake::parse(input) {
+            Some(Handshake::Login { username, password }) => self
+                .try_login(username, password)
+                .await
+                .map(|_| username.to_string()),
+            Some(Handshake::Register { username, password }) => self
+                .try_register(username, password)
+                .await
+                .map(|_| username.to_string()),
+            None => Err(AuthFailure::BadRequest),
+        };
+
+        match result {
+            Ok(username) => {
+                let event = Event::AuthResult {
+                    ok: true,
+                    code: "ok".to_string(),
+                    username: Some(username.clone()),
+                    message: format!("Welcome {username}!"),
+                };
+                writer.write_event(None, event).await?;
+                info!(user = %username, "authenticated via handshake");
+                Ok(Some(username))
+            }
+            Err(failure) => {
+                let failure = match failure {
+                    AuthFailure::UnknownUser | AuthFailure::WrongPassword => {
+                        AuthFailure::InvalidCredentials
+                    }
+                    failure => failure,
+                };
+                let event = Event::AuthResult {
+                    ok: false,
+                    code: failure.code().to_string(),
+                    username: None,
+                    message: failure.message().to_string(),
+                };
+                writer.write_event(None, event).await?;
+                warn!(code = failure.code(), "handshake failed");
+                self.check_failures(writer).await?;
+                Ok(None)
+            }
+        }
+    }
+
     async fn register(
-        &self,
-        writer: &mut OwnedWriteHalf,
-        reader: &mut BufReader<&mut OwnedReadHalf>,
+        &mut self,
+        writer: &mut FrameWriter,
+        reader: &mut FrameReader,
     ) -> Result<String> {
         loop {
             let Ok((username, password)) = Self::credentials(writer, reader).await else {
@@ -57,34 +265,29 @@ impl Auth {
             };
             let username = username.trim();
             let password = password.trim();
-            if !password.is_empty() && !username.is_empty() {
-                let password_hash = Self::hash_password(password.to_string().clone()).await;
-                println!("INFO: password hash: {password_hash}");
-                match self
-                    .user_db
-                    .create_user(username.to_string(), password_hash.as_str())
-                    .await
-                {
-                    Ok(username) => println!("INFO: {:#?} created", username),
-                    Err(e) => {
-                        println!("ERROR: failed to create user {username}: {}", e);
-                        let response = format!("{username} is taken. Chosse another username.\n");
-                        Self::write_line(writer, response.as_str()).await?;
-                        continue;
-                    }
+            match self.try_register(username, password).await {
+                Ok(()) => {
+                    let response = format!("Welcome {username}!\n");
+                    Self::write_line(writer, response.as_str()).await?;
+                    break Ok(username.to_string());
+                }
+                Err(AuthFailure::UsernameTaken) => {
+                    let response = format!("{username} is taken. Chosse another username.\n");
+                    Self::write_line(writer, response.as_str()).await?;
+                }
+                Err(failure @ (AuthFailure::TooManyAttempts | AuthFailure::Invalid(_))) => {
+                    Self::write_line(writer, failure.message()).await?;
                 }
-                let response = format!("Welcome {username}!\n");
-                Self::write_line(writer, response.as_str()).await?;
-                Self::list_users(&self.user_db).await?;
-                break Ok(username.to_string());
+                Err(_) => {}
             }
+            self.check_failures(writer).await?;
         }
     }
 
     async fn login(
-        &self,
-        writer: &mut OwnedWriteHalf,
-        reader: &mut BufReader<&mut OwnedReadHalf>,
+        &mut self,
+        writer: &mut FrameWriter,
+        reader: &mut FrameReader,
     ) -> Result<String> {
         loop {
             let Ok((username, password)) = Self::credentials(writer, reader).await else {
@@ -93,41 +296,172 @@ impl Auth {
                     "failed to read credentials",
                 ));
             };
-            let user = self.user_db.find_by_username(username.as_str()).await;
-            match user {
-                Ok(Some(user)) => {
-                    let is_valid = Self::verify_password(password, &user.password_hash).await;
-                    if is_valid {
-                        let response = format!("Welcome back {username}!\n");
-                        Self::write_line(writer, response.as_str()).await?;
-                        let username = username.trim();
-                        println!("INFO: {username} logged in");
-                        Self::list_users(&self.user_db).await?;
-                        break Ok(username.to_string());
-                    } else {
-                        Self::write_line(writer, "You entered wrong password").await?;
-                        println!("WARN: user entered wrong password");
-                        continue;
-                    }
+            match self.try_login(&username, &password).await {
+                Ok(()) => {
+                    let response = format!("Welcome back {username}!\n");
+                    Self::write_line(writer, response.as_str()).await?;
+                    break Ok(username.trim().to_string());
                 }
-                Ok(None) => {
+                Err(AuthFailure::WrongPassword) => {
+                    Self::write_line(writer, "You entered wrong password").await?;
+                }
+                Err(AuthFailure::UnknownUser) => {
                     Self::write_line(writer, "user doesn't exist").await?;
-                    println!("WARN: user doesn't exist");
                 }
-                Err(e) => {
-                    println!("ERROR: failed to login: {}", e);
-                    continue;
+                Err(failure @ (AuthFailure::TooManyAttempts | AuthFailure::Invalid(_))) => {
+                    Self::write_line(writer, failure.message()).await?;
+                }
+                Err(_) => {}
+            }
+            self.check_failures(writer).await?;
+        }
+    }
+
+    /// Drops the connection once it has used up its attempts.
+    async fn check_failures(&self, writer: &mut FrameWriter) -> Result<()> {
+        if self.failures < MAX_CONNECTION_FAILURES {
+            return Ok(());
+        }
+        Self::write_line(writer, "Too many failed attempts, disconnecting.").await?;
+        Err(Error::new(
+            ErrorKind::PermissionDenied,
+            "too many failed authentication attempts",
+        ))
+    }
+
+    /// Counts a failed attempt against the connection and, when it cost a
+    /// password check, against the client address.
+    async fn record(&mut self, result: &std::result::Result<(), AuthFailure>) {
+        match result {
+            Ok(()) | Err(AuthFailure::Unavailable) => {}
+            Err(failure) => {
+                self.failures += 1;
+                if matches!(
+                    failure,
+                    AuthFailure::WrongPassword
+                        | AuthFailure::UnknownUser
+                        | AuthFailure::UsernameTaken
+                ) {
+                    self.limiter.record_failure(self.peer).await;
                 }
             }
         }
     }
 
+    async fn try_register(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> std::result::Result<(), AuthFailure> {
+        let result = if self.limiter.allows(self.peer).await {
+            self.create_account(username, password).await
+        } else {
+            Err(AuthFailure::TooManyAttempts)
+        };
+        telemetry::auth_attempt("register", Self::outcome(&result));
+        self.record(&result).await;
+        result
+    }
+
+    async fn create_account(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> std::result::Result<(), AuthFailure> {
+        validation::check(&Registration { username, password }).map_err(AuthFailure::Invalid)?;
+        let password_hash = Self::hash_password(password.to_string()).await;
+        if let Err(e) = self
+            .user_db
+            .create_user(username.to_string(), password_hash.as_str())
+            .await
+        {
+            warn!(user = %username, error = %e, "failed to create account");
+            return Err(AuthFailure::UsernameTaken);
+        }
+        Ok(())
+    }
+
+    async fn try_login(
+        &mut self,
+        username: &str,
+        password: &str,
+    ) -> std::result::Result<(), AuthFailure> {
+        let result = if self.limiter.allows(self.peer).await {
+            self.check_login(username, password).await
+        } else {
+            Err(AuthFailure::TooManyAttempts)
+        };
+        telemetry::auth_attempt("login", Self::outcome(&result));
+        self.record(&result).await;
+        result
+    }
+
+    async fn check_login(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> std::result::Result<(), AuthFailure> {
+        validation::check(&Login { password }).map_err(AuthFailure::Invalid)?;
+        match self.user_db.find_by_username(username).await {
+            Ok(Some(user)) => {
+                if user.locked_until.is_some_and(|until| until > Utc::now()) {
+                    warn!(user = %user.username, "login to locked account");
+                    return Err(AuthFailure::TooManyAttempts);
+                }
+                if Self::verify_password(password.to_string(), &user.password_hash).await {
+                    if let Err(e) = self.user_db.reset_failed_logins(&user.username).await {
+                        error!(user = %user.username, error = %e, "failed to reset failed logins");
+                    }
+                    info!(user = %username.trim(), "logged in");
+                    Ok(())
+                } else {
+                    match self
+                        .user_db
+                        .record_failed_login(
+                            &user.username,
+                            LOCKOUT_THRESHOLD,
+                            LOCKOUT_BASE_SECS,
+                            LOCKOUT_MAX_SECS,
+                        )
+                        .await
+                    {
+                        Ok(Some(until)) => {
+                            warn!(user = %user.username, %until, "wrong password, account locked")
+                        }
+                        Ok(None) => warn!(user = %user.username, "wrong password"),
+                        Err(e) => {
+                            error!(user = %user.username, error = %e, "failed to record failed login")
+                        }
+                    }
+                    Err(AuthFailure::WrongPassword)
+                }
+            }
+            Ok(None) => {
+                warn!(user = %username.trim(), "unknown user");
+                Err(AuthFailure::UnknownUser)
+            }
+            Err(e) => {
+                error!(error = %e, "failed to look up user");
+                Err(AuthFailure::Unavailable)
+            }
+        }
+    }
+
+    /// Metric label for an authentication attempt.
+    fn outcome(result: &std::result::Result<(), AuthFailure>) -> &'static str {
+        match result {
+            Ok(()) => "success",
+            Err(AuthFailure::Unavailable) => "error",
+            Err(_) => "failure",
+        }
+    }
+
     async fn credentials(
-        writer: &mut OwnedWriteHalf,
-        reader: &mut BufReader<&mut OwnedReadHalf>,
+        writer: &mut FrameWriter,
+        reader: &mut FrameReader,
     ) -> Result<(String, String)> {
-        let username = Self::read_input(writer, reader, "Enter username \n").await?;
-        let password = Self::read_input(writer, reader, "Enter password \n").await?;
+        let username = Self::read_input(writer, reader, "Enter username ").await?;
+        let password = Self::read_input(writer, reader, "Enter password ").await?;
         Ok((username, password))
     }
 
@@ -141,27 +475,6 @@ impl Auth {
         hash
     }
 
-    pub async fn list_users(user_db: &UserDb) -> Result<()> {
-        match user_db.get_all_users().await {
-            Ok(users) => {
-                if users.is_empty() {
-                    println!("INFO: no users found in the database.");
-                } else {
-                    println!("=== Users in Database ===");
-                    println!("{:<5} {:<20} {:<25}", "ID", "Username", "Created At");
-                    println!("{}", "-".repeat(55));
-                    for user in users.clone() {
-                        let created_at = user.created_at.format("%Y-%m-%d %H:%M:%S");
-                        println!("{:<5} {:<20} {:<25}", user.id, user.username, created_at);
-                    }
-                    println!("Total: {} users", users.len());
-                }
-            }
-            Err(e) => println!("ERROR: fetching users: {}", e),
-        }
-        Ok(())
-    }
-
     async fn verify_password(password: String, stored_hash: &str) -> bool {
         let parsed_hash = PasswordHash::new(stored_hash).expect("ERROR: invalid hash format");
         Argon2::default()
@@ -169,28 +482,38 @@ impl Auth {
             .is_ok()
     }
 
-    async fn write_line(writer: &mut OwnedWriteHalf, message: &str) -> Result<()> {
-        writer
-            .write_all(format!("{}\n", message).as_bytes())
-            .await?;
-        writer.flush().await?;
-        Ok(())
+    async fn write_line(writer: &mut FrameWriter, message: &str) -> Result<()> {
+        let text = message.strip_suffix('\n').unwrap_or(message).to_string();
+        writer.write_event(None, Event::Info { text }).await
     }
 
     async fn read_input(
-        writer: &mut OwnedWriteHalf,
-        reader: &mut BufReader<&mut OwnedReadHalf>,
+        writer: &mut FrameWriter,
+        reader: &mut FrameReader,
         prompt: &str,
     ) -> Result<String> {
-        Self::prompt_user(writer, prompt).await?;
-        let mut input = String::new();
-        reader.read_line(&mut input).await?;
-        Ok(input.trim().to_string())
+        loop {
+            Self::prompt_user(writer, prompt).await?;
+            match reader.read_input().await {
+                Ok(Some(input)) => return Ok(input.text),
+                Ok(None) => {
+                    return Err(Error::new(
+                        ErrorKind::UnexpectedEof,
+                        "client disconnected during authentication",
+                    ));
+                }
+                // The over-long line was skipped; say so and ask again.
+                Err(e) if e.kind() == ErrorKind::InvalidInput => {
+                    let event = Event::error("bad_request", e.to_string());
+                    writer.write_event(None, event).await?;
+                }
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    async fn prompt_user(writer: &mut OwnedWriteHalf, prompt: &str) -> Result<()> {
-        writer.write_all(prompt.as_bytes()).await?;
-        writer.flush().await?;
-        Ok(())
+    async fn prompt_user(writer: &mut FrameWriter, prompt: &str) -> Result<()> {
+        let text = prompt.to_string();
+        writer.write_event(None, Event::Prompt { text }).await
     }
 }
@@ -0,0 +1,851 @@
+use chrono::TimeDelta;
+
+use crate::models::Role;
+use crate::protocol::Event;
+
+const MAX_HISTORY_LIMIT: i64 = 100;
+
+#[derive(Debug)]
+pub enum Command {
+    PrivateMessage {
+        target: String,
+        message: String,
+    },
+    SendFile {
+        target: String,
+        spec: String,
+    },
+    AcceptFile(i64),
+    ListFiles,
+    JoinChannel(String),
+    CreateChannel {
+        name: String,
+        topic: Option<String>,
+    },
+    SetTopic(String),
+    ListUsers,
+    ListChannels,
+    CloseConnection,
+    KickUser(String),
+    Ban {
+        target: String,
+        duration: Option<TimeDelta>,
+        reason: Option<String>,
+    },
+    Unban(String),
+    Mute {
+        target: String,
+        duration: Option<TimeDelta>,
+    },
+    Unmute(String),
+    Op(String),
+    Deop(String),
+    Broadcast(String),
+    ProfileView,
+    Search {
+        query: String,
+        channel: Option<String>,
+        sender: Option<String>,
+    },
+    History {
+        target: String,
+        limit: Option<i64>,
+        before: Option<i64>,
+    },
+    GrantRole {
+        target: String,
+        role: Role,
+    },
+    RevokeRole(String),
+    Help(Option<String>),
+}
+
+/// One entry in a command's argument list.
+#[derive(Debug, Clone, Copy)]
+pub enum Arg {
+    /// A single word, such as a username.
+    Word(&'static str),
+    /// A single word that may be left out.
+    OptionalWord(&'static str),
+    /// The rest of the line. `usage` is shown in the usage line as written.
+    Rest { usage: &'static str, required: bool },
+}
+
+/// The arguments of one invocation, in the order the command declares them.
+#[derive(Debug)]
+pub struct Args<'a> {
+    values: Vec<Option<&'a str>>,
+}
+
+impl<'a> Args<'a> {
+    pub fn get(&self, index: usize) -> Option<&'a str> {
+        self.values.get(index).copied().flatten()
+    }
+
+    /// A required argument. Its presence is checked before parsing.
+    pub fn word(&self, index: usize) -> String {
+        self.get(index).unwrap_or_default().to_string()
+    }
+}
+
+/// Everything the server knows about a command: how it is called, who may
+/// call it and how it is explained in `/help`.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    pub args: &'static [Arg],
+    /// Lowest server role allowed to run the command.
+    pub role: Role,
+    pub summary: &'static str,
+    /// Shown by `/help <command>` below the summary. May be empty.
+    pub help: &'static str,
+    /// Turns checked arguments into a command. Errors are shown to the
+    /// user followed by the usage line.
+    parse: fn(&Args) -> Result<Command, String>,
+}
+
+/// A parsed line and the command it came from; chat has no command.
+pub struct Invocation {
+    pub command: Command,
+    pub spec: Option<&'static CommandSpec>,
+}
+
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "msg",
+        aliases: &[],
+        args: &[
+            Arg::Word("user"),
+            Arg::Rest {
+                usage: "<message>",
+                required: true,
+            },
+        ],
+        role: Role::User,
+        summary: "Send a private message",
+        help: "Users who are offline get the message when they next log in.",
+        parse: |args| {
+            Ok(Command::PrivateMessage {
+                target: args.word(0),
+                message: args.word(1),
+            })
+        },
+    },
+    CommandSpec {
+        name: "send",
+        aliases: &[],
+        args: &[
+            Arg::Word("user"),
+            Arg::Rest {
+                usage: "<name> <size> <sha256>",
+                required: true,
+            },
+        ],
+        role: Role::User,
+        summary: "Upload a file for a user",
+        help: "The file's bytes follow once the server answers that it is ready. \
+               The recipient is offered the file and can download it with /accept.",
+        parse: |args| {
+            Ok(Command::SendFile {
+                target: args.word(0),
+                spec: args.word(1),
+            })
+        },
+    },
+    CommandSpec {
+        name: "files",
+        aliases: &[],
+        args: &[],
+        role: Role::User,
+        summary: "List files sent to you",
+        help: "",
+        parse: |_| Ok(Command::ListFiles),
+    },
+    CommandSpec {
+        name: "accept",
+        aliases: &[],
+        args: &[Arg::Word("id")],
+        role: Role::User,
+        summary: "Download a file sent to you",
+        help: "The id is shown in the file offer and in /files.",
+        parse: |args| match args.word(0).parse() {
+            Ok(id) => Ok(Command::AcceptFile(id)),
+            Err(_) => Err(format!("'{}' is not a file id", args.word(0))),
+        },
+    },
+    CommandSpec {
+        name: "join",
+        aliases: &[],
+        args: &[Arg::Word("channel")],
+        role: Role::User,
+        summary: "Switch channels",
+        help: "Channels that do not exist yet are created.",
+        parse: |args| Ok(Command::JoinChannel(args.word(0))),
+    },
+    CommandSpec {
+        name: "create",
+        aliases: &[],
+        args: &[
+            Arg::Word("channel"),
+            Arg::Rest {
+                usage: "[topic]",
+                required: false,
+            },
+        ],
+        role: Role::User,
+        summary: "Create a channel",
+        help: "",
+        parse: |args| {
+            Ok(Command::CreateChannel {
+                name: args.word(0),
+                topic: args.get(1).map(str::to_string),
+            })
+        },
+    },
+    CommandSpec {
+        name: "topic",
+        aliases: &[],
+        args: &[Arg::Rest {
+            usage: "<topic>",
+            required: true,
+        }],
+        role: Role::User,
+        summary: "Set the topic of your channel",
+        help: "",
+        parse: |args| Ok(Command::SetTopic(args.word(0))),
+    },
+    CommandSpec {
+        name: "list",
+        aliases: &["who"],
+        args: &[],
+        role: Role::User,
+        summary: "List online users",
+        help: "",
+        parse: |_| Ok(Command::ListUsers),
+    },
+    CommandSpec {
+        name: "channels",
+        aliases: &[],
+        args: &[],
+        role: Role::User,
+        summary: "List all channels",
+        help: "",
+        parse: |_| Ok(Command::ListChannels),
+    },
+    CommandSpec {
+        name: "profile",
+        aliases: &[],
+        args: &[],
+        role: Role::User,
+        summary: "Show your profile",
+        help: "",
+        parse: |_| Ok(Command::ProfileView),
+    },
+    CommandSpec {
+        name: "history",
+        aliases: &[],
+        args: &[
+            Arg::Word("user|#channel"),
+            Arg::Rest {
+                usage: "[count|before <id>]",
+                required: false,
+            },
+        ],
+        role: Role::User,
+        summary: "Show stored messages",
+        help: "Shows your conversation with a user, or a channel when the name \
+               starts with '#'. Add a count of up to 100 messages, or 'before <id>' \
+               for the page before a message.",
+        parse: parse_history,
+    },
+    CommandSpec {
+        name: "search",
+        aliases: &[],
+        args: &[Arg::Rest {
+            usage: "<query> [in #channel] [from user]",
+            required: true,
+        }],
+        role: Role::User,
+        summary: "Search messages",
+        help: "Supports quoted phrases, 'or' and '-word'.",
+        parse: |args| parse_search(args.get(0).unwrap_or_default()),
+    },
+    CommandSpec {
+        name: "kick",
+        aliases: &[],
+        args: &[Arg::Word("user")],
+        role: Role::Mod,
+        summary: "Disconnect a user from the server",
+        help: "Only users ranked below you can be kicked.",
+        parse: |args| Ok(Command::KickUser(args.word(0))),
+    },
+    CommandSpec {
+        name: "ban",
+        aliases: &[],
+        args: &[
+            Arg::Word("user"),
+            Arg::Rest {
+                usage: "[duration] [reason]",
+                required: false,
+            },
+        ],
+        role: Role::User,
+        summary: "Ban a user from your channel",
+        help: "Channel operators, the channel creator and Mods can ban users who \
+               rank below them. Durations look like 30s, 10m, 2h or 7d; without \
+               one the ban is permanent.",
+        parse: parse_ban,
+    },
+    CommandSpec {
+        name: "unban",
+        aliases: &[],
+        args: &[Arg::Word("user")],
+        role: Role::User,
+        summary: "Lift a ban in your channel",
+        help: "",
+        parse: |args| Ok(Command::Unban(args.word(0))),
+    },
+    CommandSpec {
+        name: "mute",
+        aliases: &[],
+        args: &[Arg::Word("user"), Arg::OptionalWord("duration")],
+        role: Role::User,
+        summary: "Mute a user in your channel",
+        help: "Muted users cannot talk in the channel. Durations look like 30s, \
+               10m, 2h or 7d; without one the mute is permanent.",
+        parse: |args| {
+            let duration = match args.get(1) {
+                Some(duration) => Some(parse_duration(duration)?),
+                None => None,
+            };
+            Ok(Command::Mute {
+                target: args.word(0),
+                duration,
+            })
+        },
+    },
+    CommandSpec {
+        name: "unmute",
+        aliases: &[],
+        args: &[Arg::Word("user")],
+        role: Role::User,
+        summary: "Lift a mute in your channel",
+        help: "",
+        parse: |args| Ok(Command::Unmute(args.word(0))),
+    },
+    CommandSpec {
+        name: "op",
+        aliases: &[],
+        args: &[Arg::Word("user")],
+        role: Role::User,
+        summary: "Make a user an operator of your channel",
+        help: "Operators can ban, mute and appoint operators in the channel.",
+        parse: |args| Ok(Command::Op(args.word(0))),
+    },
+    CommandSpec {
+        name: "deop",
+        aliases: &[],
+        args: &[Arg::Word("user")],
+        role: Role::User,
+        summary: "Remove an operator of your channel",
+        help: "",
+        parse: |args| Ok(Command::Deop(args.word(0))),
+    },
+    CommandSpec {
+        name: "grant",
+        aliases: &["role"],
+        args: &[Arg::Word("user"), Arg::Word("role")],
+        role: Role::Admin,
+        summary: "Give a user a role",
+        help: "Roles are user, mod, admin and owner. You can only hand out roles \
+               below your own, to users ranked below you.",
+        parse: |args| {
+            Ok(Command::GrantRole {
+                target: args.word(0),
+                role: args.word(1).parse()?,
+            })
+        },
+    },
+    CommandSpec {
+        name: "revoke",
+        aliases: &[],
+        args: &[Arg::Word("user")],
+        role: Role::Admin,
+        summary: "Reset a user to User",
+        help: "",
+        parse: |args| Ok(Command::RevokeRole(args.word(0))),
+    },
+    CommandSpec {
+        name: "close",
+        aliases: &["quit"],
+        args: &[],
+        role: Role::User,
+        summary: "Close the connection",
+        help: "",
+        parse: |_| Ok(Command::CloseConnection),
+    },
+    CommandSpec {
+        name: "help",
+        aliases: &[],
+        args: &[Arg::OptionalWord("command")],
+        role: Role::User,
+        summary: "List commands, or explain one",
+        help: "",
+        parse: |args| Ok(Command::Help(args.get(0).map(str::to_string))),
+    },
+];
+
+/// Finds a command by name or alias, with or without the leading `/`.
+pub fn find(name: &str) -> Option<&'static CommandSpec> {
+    let name = name.strip_prefix('/').unwrap_or(name);
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name))
+}
+
+/// Parses a line of input. Lines that do not start with `/` are chat.
+/// Errors are ready to be sent back to the user.
+pub fn parse(input: String) -> Result<Invocation, Event> {
+    if input.trim().is_empty() {
+        return Ok(Invocation {
+            command: Command::Help(None),
+            spec: find("help"),
+        });
+    }
+    let Some(line) = input.strip_prefix('/') else {
+        return Ok(Invocation {
+            command: Command::Broadcast(input),
+            spec: None,
+        });
+    };
+
+    let (name, rest) = line.split_once(' ').unwrap_or((line, ""));
+    let Some(spec) = find(name) else {
+        let response = format!("Unknown command /{name}. Type /help for a list of commands.");
+        return Err(Event::error("unknown_command", response));
+    };
+    let invalid = |problem: String| {
+        let response = format!("{problem}. Usage: {}", spec.usage());
+        Event::error("bad_request", response)
+    };
+    let args = spec.split(rest).map_err(invalid)?;
+    let command = (spec.parse)(&args).map_err(invalid)?;
+    Ok(Invocation {
+        command,
+        spec: Some(spec),
+    })
+}
+
+/// Lists every command, one per line.
+pub fn overview() -> String {
+    let mut lines = vec!["Available commands:".to_string()];
+    for spec in COMMANDS {
+        let mut line = format!("  {} - {}", spec.usage(), spec.summary);
+        if spec.role > Role::User {
+            line.push_str(&format!(" ({} and above)", spec.role));
+        }
+        lines.push(line);
+    }
+    lines.push("Type /help <command> for details.".to_string());
+    lines.join("\n")
+}
+
+impl CommandSpec {
+    pub fn usage(&self) -> String {
+        let mut usage = format!("/{}", self.name);
+        for arg in self.args {
+            usage.push(' ');
+            match arg {
+                Arg::Word(name) => usage.push_str(&format!("<{name}>")),
+                Arg::OptionalWord(name) => usage.push_str(&format!("[{name}]")),
+                Arg::Rest { usage: rest, .. } => usage.push_str(rest),
+            }
+        }
+        usage
+    }
+
+    /// The full `/help <command>` text.
+    pub fn describe(&self) -> String {
+        let mut lines = vec![self.usage(), format!("{}.", self.summary)];
+        if !self.help.is_empty() {
+            lines.push(self.help.to_string());
+        }
+        if self.role > Role::User {
+            lines.push(format!("Requires {} or above.", self.role));
+        }
+        if !self.aliases.is_empty() {
+            let aliases: Vec<String> = self.aliases.iter().map(|a| format!("/{a}")).collect();
+            lines.push(format!("Also available as {}.", aliases.join(", ")));
+        }
+        lines.join("\n")
+    }
+
+    /// Splits `input` into the declared arguments, checking that required
+    /// ones are present and nothing is left over.
+    fn split<'a>(&self, input: &'a str) -> Result<Args<'a>, String> {
+        let mut rest = input.trim();
+        let mut values = Vec::with_capacity(self.args.len());
+        for arg in self.args {
+            let value = match arg {
+                Arg::Word(_) | Arg::OptionalWord(_) => {
+                    let (word, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+                    rest = remaining.trim_start();
+                    Some(word).filter(|word| !word.is_empty())
+                }
+                Arg::Rest { .. } => Some(std::mem::take(&mut rest)).filter(|rest| !rest.is_empty()),
+            };
+            match (arg, value) {
+                (Arg::Word(name), None) => return Err(format!("Missing <{name}>")),
+                (
+                    Arg::Rest {
+                        usage,
+                        required: true,
+                    },
+                    None,
+                ) => {
+                    return Err(format!("Missing {usage}"));
+                }
+                _ => values.push(value),
+            }
+        }
+        if !rest.is_empty() {
+            return Err(format!("Unexpected '{rest}'"));
+        }
+        Ok(Args { values })
+    }
+}
+
+fn parse_ban(args: &Args) -> Result<Command, String> {
+    let rest = args.get(1).unwrap_or_default();
+    let (first, remaining) = rest.split_once(' ').unwrap_or((rest, ""));
+    let (duration, reason) = match parse_duration(first) {
+        Ok(duration) => (Some(duration), remaining.trim()),
+        Err(_) => (None, rest),
+    };
+    Ok(Command::Ban {
+        target: args.word(0),
+        duration,
+        reason: Some(reason)
+            .filter(|reason| !reason.is_empty())
+            .map(str::to_string),
+    })
+}
+
+fn parse_history(args: &Args) -> Result<Command, String> {
+    let target = args.word(0);
+    let Some(page) = args.get(1) else {
+        return Ok(Command::History {
+            target,
+            limit: None,
+            before: None,
+        });
+    };
+    match page.split_once(' ') {
+        Some(("before", id)) => match id.trim().parse() {
+            Ok(id) => Ok(Command::History {
+                target,
+                limit: None,
+                before: Some(id),
+            }),
+            Err(_) => Err(format!("'{}' is not a message id", id.trim())),
+        },
+        Some(_) => Err(format!("Expected a count or 'before <id>', not '{page}'")),
+        None => match page.parse::<i64>() {
+            Ok(count) if count > 0 => Ok(Command::History {
+                target,
+                limit: Some(count.min(MAX_HISTORY_LIMIT)),
+                before: None,
+            }),
+            _ => Err(format!("'{page}' is not a positive count")),
+        },
+    }
+}
+
+/// Parses `<query> [in #channel] [from user]`, with the filters in either
+/// order at the end of the line.
+fn parse_search(input: &str) -> Result<Command, String> {
+    let mut words: Vec<&str> = input.split_whitespace().collect();
+    let mut channel = None;
+    let mut sender = None;
+    loop {
+        match words.as_slice() {
+            [.., "in", name] if channel.is_none() && name.len() > 1 && name.starts_with('#') => {
+                channel = Some(name[1..].to_string());
+            }
+            [.., "from", name] if sender.is_none() => {
+                sender = Some(name.to_string());
+            }
+            _ => break,
+        }
+        words.truncate(words.len() - 2);
+    }
+
+    if words.is_empty() {
+        return Err("Missing <query>".to_string());
+    }
+    Ok(Command::Search {
+        query: words.join(" "),
+        channel,
+        sender,
+    })
+}
+
+/// Parses durations such as `30s`, `10m`, `2h` or `7d`.
+fn parse_duration(input: &str) -> Result<TimeDelta, String> {
+    let invalid = || format!("'{input}' is not a duration like 30s, 10m, 2h or 7d");
+    let split = input.len().checked_sub(1).ok_or_else(invalid)?;
+    let (amount, unit) = input.split_at_checked(split).ok_or_else(invalid)?;
+    let amount: i64 = amount
+        .parse()
+        .ok()
+        .filter(|amount| *amount > 0)
+        .ok_or_else(invalid)?;
+    match unit {
+        "s" => TimeDelta::try_seconds(amount),
+        "m" => TimeDelta::try_minutes(amount),
+        "h" => TimeDelta::try_hours(amount),
+        "d" => TimeDelta::try_days(amount),
+        _ => None,
+    }
+    .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        match parse(line.to_string()) {
+            Ok(invocation) => invocation.command,
+            Err(event) => panic!("{line:?} failed to parse: {event:?}"),
+        }
+    }
+
+    fn error(line: &str) -> (String, String) {
+        match parse(line.to_string()) {
+            Ok(invocation) => panic!("{line:?} parsed as {:?}", invocation.command),
+            Err(Event::Error { code, message }) => (code, message),
+            Err(event) => panic!("{line:?} failed with {event:?}"),
+        }
+    }
+
+    #[test]
+    fn plain_and_empty_lines() {
+        assert!(
+            matches!(command("hello /there"), Command::Broadcast(text) if text == "hello /there")
+        );
+        assert!(matches!(command(""), Command::Help(None)));
+        assert!(matches!(command("   "), Command::Help(None)));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        let (code, message) = error("/frobnicate now");
+        assert_eq!(code, "unknown_command");
+        assert_eq!(
+            message,
+            "Unknown command /frobnicate. Type /help for a list of commands."
+        );
+        assert_eq!(error("/").0, "unknown_command");
+    }
+
+    #[test]
+    fn argument_errors_carry_the_usage_line() {
+        assert_eq!(
+            error("/kick"),
+            (
+                "bad_request".to_string(),
+                "Missing <user>. Usage: /kick <user>".to_string()
+            )
+        );
+        assert_eq!(
+            error("/kick bob now").1,
+            "Unexpected 'now'. Usage: /kick <user>"
+        );
+        assert_eq!(
+            error("/msg bob").1,
+            "Missing <message>. Usage: /msg <user> <message>"
+        );
+        assert_eq!(
+            error("/accept x").1,
+            "'x' is not a file id. Usage: /accept <id>"
+        );
+        assert_eq!(
+            error("/grant bob king").1,
+            "Unknown role: king. Usage: /grant <user> <role>"
+        );
+    }
+
+    #[test]
+    fn aliases_resolve_to_their_command() {
+        let invocation = parse("/who".to_string()).unwrap();
+        assert!(matches!(invocation.command, Command::ListUsers));
+        assert_eq!(invocation.spec.unwrap().name, "list");
+
+        let invocation = parse("/role bob mod".to_string()).unwrap();
+        assert!(matches!(
+            invocation.command,
+            Command::GrantRole { target, role: Role::Mod } if target == "bob"
+        ));
+        assert_eq!(invocation.spec.unwrap().role, Role::Admin);
+    }
+
+    #[test]
+    fn rest_arguments_keep_their_spaces() {
+        assert!(matches!(
+            command("/msg bob  hi  there "),
+            Command::PrivateMessage { target, message } if target == "bob" && message == "hi  there"
+        ));
+        assert!(
+            matches!(command("/topic  Rust talk"), Command::SetTopic(topic) if topic == "Rust talk")
+        );
+        assert!(matches!(
+            command("/create rust"),
+            Command::CreateChannel { name, topic: None } if name == "rust"
+        ));
+    }
+
+    #[test]
+    fn ban_takes_an_optional_duration_before_the_reason() {
+        assert!(matches!(
+            command("/ban bob 10m spamming links"),
+            Command::Ban { target, duration: Some(duration), reason: Some(reason) }
+                if target == "bob" && duration == TimeDelta::minutes(10) && reason == "spamming links"
+        ));
+        assert!(matches!(
+            command("/ban bob being rude"),
+            Command::Ban { duration: None, reason: Some(reason), .. } if reason == "being rude"
+        ));
+        assert!(matches!(
+            command("/ban bob"),
+            Command::Ban {
+                duration: None,
+                reason: None,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn mute_rejects_bad_durations() {
+        assert!(matches!(
+            command("/mute bob 2h"),
+            Command::Mute { duration: Some(duration), .. } if duration == TimeDelta::hours(2)
+        ));
+        assert_eq!(
+            error("/mute bob 5x").1,
+            "'5x' is not a duration like 30s, 10m, 2h or 7d. Usage: /mute <user> [duration]"
+        );
+    }
+
+    #[test]
+    fn history_pages() {
+        assert!(matches!(
+            command("/history #rust"),
+            Command::History {
+                limit: None,
+                before: None,
+                ..
+            }
+        ));
+        assert!(matches!(
+            command("/history bob 500"),
+            Command::History {
+                limit: Some(MAX_HISTORY_LIMIT),
+                ..
+            }
+        ));
+        assert!(matches!(
+            command("/history bob before 42"),
+            Command::History {
+                limit: None,
+                before: Some(42),
+                ..
+            }
+        ));
+        assert!(
+            error("/history bob 0")
+                .1
+                .starts_with("'0' is not a positive count")
+        );
+        assert!(
+            error("/history bob after 3")
+                .1
+                .starts_with("Expected a count")
+        );
+        assert!(
+            error("/history bob before x")
+                .1
+                .starts_with("'x' is not a message id")
+        );
+    }
+
+    #[test]
+    fn search_filters_come_off_the_end() {
+        assert!(matches!(
+            command("/search \"borrow checker\" in #rust from bob"),
+            Command::Search { query, channel: Some(channel), sender: Some(sender) }
+                if query == "\"borrow checker\"" && channel == "rust" && sender == "bob"
+        ));
+        assert!(matches!(
+            command("/search traits from bob in #rust"),
+            Command::Search { query, channel: Some(channel), sender: Some(sender) }
+                if query == "traits" && channel == "rust" && sender == "bob"
+        ));
+        assert!(matches!(
+            command("/search in # from"),
+            Command::Search { query, channel: None, sender: None } if query == "in # from"
+        ));
+        assert_eq!(
+            error("/search from bob").1,
+            "Missing <query>. Usage: /search <query> [in #channel] [from user]"
+        );
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("30s"), Ok(TimeDelta::seconds(30)));
+        assert_eq!(parse_duration("10m"), Ok(TimeDelta::minutes(10)));
+        assert_eq!(parse_duration("2h"), Ok(TimeDelta::hours(2)));
+        assert_eq!(parse_duration("7d"), Ok(TimeDelta::days(7)));
+        for input in ["", "m", "10", "0m", "-5m", "10x", "1.5h", "é", "10mm"] {
+            assert!(parse_duration(input).is_err(), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn durations_that_overflow_are_rejected() {
+        assert!(parse_duration("99999999999999999999d").is_err());
+        assert!(parse_duration("9223372036854775807s").is_err());
+        assert!(parse_duration("999999999999999d").is_err());
+    }
+
+    #[test]
+    fn every_name_and_alias_is_unique() {
+        let mut names: Vec<&str> = COMMANDS
+            .iter()
+            .flat_map(|spec| std::iter::once(spec.name).chain(spec.aliases.iter().copied()))
+            .collect();
+        let count = names.len();
+        names.sort_unstable();
+        names.dedup();
+        assert_eq!(names.len(), count);
+    }
+
+    #[test]
+    fn help_lists_every_command() {
+        let overview = overview();
+        for spec in COMMANDS {
+            assert!(overview.contains(&spec.usage()), "{}", spec.name);
+        }
+        let kick = find("/kick").unwrap().describe();
+        assert!(kick.starts_with("/kick <user>\n"));
+        assert!(kick.contains("Requires Mod or above."));
+        assert!(
+            find("quit")
+                .unwrap()
+                .describe()
+                .contains("Also available as /quit.")
+        );
+    }
+}
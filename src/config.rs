@@ -0,0 +1,400 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::flood::{FloodLimits, Rate};
+use crate::logging::{LogConfig, LogFormat};
+use crate::tls::TlsConfig;
+use crate::validation::{self, ChannelName};
+
+const DEFAULT_CONFIG_PATH: &str = "wur2.toml";
+
+const DEFAULT_USER_RATE: Rate = Rate {
+    messages_per_sec: 1.0,
+    bytes_per_sec: 1024.0,
+    burst_secs: 5.0,
+};
+const DEFAULT_CHANNEL_RATE: Rate = Rate {
+    messages_per_sec: 10.0,
+    bytes_per_sec: 10240.0,
+    burst_secs: 5.0,
+};
+/// Upper bound for automatic flood mutes: one year.
+const MAX_FLOOD_MUTE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+
+/// Command-line flags. Each flag can also be set through the environment
+/// variable next to it, and falls back to the config file and then to the
+/// built-in default.
+#[derive(Debug, Parser)]
+#[command(name = "server", about = "Whats Up Rust 2 chat server")]
+struct Args {
+    /// TOML config file, `wur2.toml` is used when present.
+    #[arg(long, env = "WUR2_CONFIG")]
+    config: Option<PathBuf>,
+    /// PostgreSQL connection string.
+    #[arg(long, env = "DATABASE_URL", hide_env_values = true)]
+    database_url: Option<String>,
+    /// Size of the database connection pool [default: 5].
+    #[arg(long, env = "DB_MAX_CONNECTIONS")]
+    db_max_connections: Option<u32>,
+    /// Address of the plain TCP listener [default: 0.0.0.0:6969].
+    #[arg(long = "bind", env = "BIND_ADDR")]
+    bind_addr: Option<String>,
+    /// Channel users start in after logging in [default: Global].
+    #[arg(long, env = "DEFAULT_CHANNEL")]
+    default_channel: Option<String>,
+    /// Message of the day shown after the welcome banner.
+    #[arg(long, env = "MOTD")]
+    motd: Option<String>,
+    /// Seconds to wait for connections to close on shutdown [default: 10].
+    #[arg(long, env = "SHUTDOWN_TIMEOUT")]
+    shutdown_timeout: Option<u64>,
+    /// Seconds without input after which a client is disconnected [default: 900].
+    #[arg(long, env = "IDLE_TIMEOUT")]
+    idle_timeout: Option<u64>,
+    /// Seconds between heartbeat pings to framed clients [default: 30].
+    #[arg(long, env = "PING_INTERVAL")]
+    ping_interval: Option<u64>,
+    /// Seconds a client gets to log in or register [default: 60].
+    #[arg(long, env = "AUTH_TIMEOUT")]
+    auth_timeout: Option<u64>,
+    /// Directory for uploaded files [default: uploads].
+    #[arg(long, env = "FILE_STORAGE_DIR")]
+    file_storage_dir: Option<PathBuf>,
+    /// Largest accepted upload in bytes [default: 52428800].
+    #[arg(long, env = "MAX_FILE_SIZE")]
+    max_file_size: Option<u64>,
+    /// Messages shown when joining a channel, 1 to 100 [default: 20].
+    #[arg(long, env = "HISTORY_LIMIT")]
+    history_limit: Option<i64>,
+    /// Messages queued per client before chat is dropped [default: 256].
+    #[arg(long, env = "OUTBOUND_QUEUE")]
+    outbound_queue: Option<usize>,
+    /// Address of the TLS listener [default: 0.0.0.0:6970].
+    #[arg(long = "tls-addr", env = "TLS_ADDR")]
+    tls_addr: Option<String>,
+    /// PEM certificate chain, enables the TLS listener.
+    #[arg(long = "tls-cert", env = "TLS_CERT_PATH")]
+    tls_cert_path: Option<PathBuf>,
+    /// PEM private key for the TLS certificate.
+    #[arg(long = "tls-key", env = "TLS_KEY_PATH")]
+    tls_key_path: Option<PathBuf>,
+    /// Address of the Prometheus metrics endpoint, disabled when unset.
+    #[arg(long, env = "METRICS_ADDR")]
+    metrics_addr: Option<String>,
+    /// Log output format [default: human].
+    #[arg(long, env = "LOG_FORMAT", value_enum)]
+    log_format: Option<LogFormat>,
+    /// Log filter such as `info` or `info,sqlx=warn` [default: info,sqlx=warn].
+    #[arg(long, env = "LOG_LEVEL")]
+    log_level: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    server: ServerSection,
+    database: DatabaseSection,
+    files: FilesSection,
+    limits: LimitsSection,
+    tls: TlsSection,
+    metrics: MetricsSection,
+    log: LogSection,
+    flood: FloodSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    bind_addr: Option<String>,
+    default_channel: Option<String>,
+    motd: Option<String>,
+    shutdown_timeout: Option<u64>,
+    idle_timeout: Option<u64>,
+    ping_interval: Option<u64>,
+    auth_timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct DatabaseSection {
+    url: Option<String>,
+    max_connections: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FilesSection {
+    storage_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LimitsSection {
+    max_file_size: Option<u64>,
+    history_limit: Option<i64>,
+    outbound_queue: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct TlsSection {
+    addr: Option<String>,
+    cert_path: Option<PathBuf>,
+    key_path: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct MetricsSection {
+    addr: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct FloodSection {
+    user: Option<Rate>,
+    channel: Option<Rate>,
+    warnings: Option<u32>,
+    mute_secs: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct LogSection {
+    format: Option<LogFormat>,
+    level: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub database_url: String,
+    pub db_max_connections: u32,
+    pub bind_addr: String,
+    pub default_channel: String,
+    pub motd: Option<String>,
+    pub shutdown_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub ping_interval: Duration,
+    pub auth_timeout: Duration,
+    pub file_storage_dir: PathBuf,
+    pub limits: Limits,
+    pub tls: Option<TlsConfig>,
+    pub metrics_addr: Option<String>,
+    pub log: LogConfig,
+    pub flood: FloodLimits,
+}
+
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub max_file_size: u64,
+    pub history_limit: i64,
+    pub outbound_queue: usize,
+}
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Builds the config from flags, environment and config file, in that
+    /// order of precedence, and validates it.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let file = Self::read_file(args.config.as_deref())?;
+
+        let tls = match (
+            args.tls_cert_path.or(file.tls.cert_path),
+            args.tls_key_path.or(file.tls.key_path),
+        ) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                addr: args
+                    .tls_addr
+                    .or(file.tls.addr)
+                    .unwrap_or("0.0.0.0:6970".to_string()),
+                cert_path,
+                key_path,
+            }),
+            (None, None) => None,
+            _ => {
+                return Err(ConfigError(
+                    "TLS needs both a certificate and a private key".to_string(),
+                ));
+            }
+        };
+
+        let config = Self {
+            database_url: args
+                .database_url
+                .or(file.database.url)
+                .ok_or(ConfigError("DATABASE_URL not set".to_string()))?,
+            db_max_connections: args
+                .db_max_connections
+                .or(file.database.max_connections)
+                .unwrap_or(5),
+            bind_addr: args
+                .bind_addr
+                .or(file.server.bind_addr)
+                .unwrap_or("0.0.0.0:6969".to_string()),
+            default_channel: args
+                .default_channel
+                .or(file.server.default_channel)
+                .unwrap_or("Global".to_string()),
+            motd: args.motd.or(file.server.motd),
+            shutdown_timeout: Duration::from_secs(
+                args.shutdown_timeout
+                    .or(file.server.shutdown_timeout)
+                    .unwrap_or(10),
+            ),
+            idle_timeout: Duration::from_secs(
+                args.idle_timeout
+                    .or(file.server.idle_timeout)
+                    .unwrap_or(900),
+            ),
+            ping_interval: Duration::from_secs(
+                args.ping_interval
+                    .or(file.server.ping_interval)
+                    .unwrap_or(30),
+            ),
+            auth_timeout: Duration::from_secs(
+                args.auth_timeout.or(file.server.auth_timeout).unwrap_or(60),
+            ),
+            file_storage_dir: args
+                .file_storage_dir
+                .or(file.files.storage_dir)
+                .unwrap_or("uploads".into()),
+            limits: Limits {
+                max_file_size: args
+                    .max_file_size
+                    .or(file.limits.max_file_size)
+                    .unwrap_or(50 * 1024 * 1024),
+                history_limit: args
+                    .history_limit
+                    .or(file.limits.history_limit)
+                    .unwrap_or(20),
+                outbound_queue: args
+                    .outbound_queue
+                    .or(file.limits.outbound_queue)
+                    .unwrap_or(256),
+            },
+            tls,
+            metrics_addr: args.metrics_addr.or(file.metrics.addr),
+            log: LogConfig {
+                format: args.log_format.or(file.log.format).unwrap_or_default(),
+                level: args
+                    .log_level
+                    .or(file.log.level)
+                    .unwrap_or("info,sqlx=warn".to_string()),
+            },
+            flood: FloodLimits {
+                user: file.flood.user.unwrap_or(DEFAULT_USER_RATE),
+                channel: file.flood.channel.unwrap_or(DEFAULT_CHANNEL_RATE),
+                warnings: file.flood.warnings.unwrap_or(3),
+                mute: Duration::from_secs(file.flood.mute_secs.unwrap_or(60)),
+            },
+        };
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn read_file(path: Option<&Path>) -> Result<FileConfig, ConfigError> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Path::new(DEFAULT_CONFIG_PATH),
+            None => return Ok(FileConfig::default()),
+        };
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError(format!("cannot read {}: {e}", path.display())))?;
+        toml::from_str(&contents)
+            .map_err(|e| ConfigError(format!("invalid config file {}: {e}", path.display())))
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let mut addrs = vec![("bind address", &self.bind_addr)];
+        if let Some(tls) = &self.tls {
+            addrs.push(("TLS address", &tls.addr));
+        }
+        if let Some(metrics_addr) = &self.metrics_addr {
+            addrs.push(("metrics address", metrics_addr));
+        }
+        for (name, addr) in addrs {
+            if addr.parse::<SocketAddr>().is_err() {
+                return Err(ConfigError(format!("invalid {name}: {addr}")));
+            }
+        }
+
+        if self.db_max_connections == 0 {
+            return Err(ConfigError(
+                "database max_connections must be at least 1".to_string(),
+            ));
+        }
+        if let Err(e) = validation::check(&ChannelName {
+            name: &self.default_channel,
+        }) {
+            return Err(ConfigError(format!(
+                "invalid default channel {:?}: {e}",
+                self.default_channel
+            )));
+        }
+        if self.auth_timeout.is_zero() || self.ping_interval.is_zero() {
+            return Err(ConfigError(
+                "auth_timeout and ping_interval must be at least 1".to_string(),
+            ));
+        }
+        if self.idle_timeout <= self.ping_interval {
+            return Err(ConfigError(
+                "idle_timeout must be longer than ping_interval".to_string(),
+            ));
+        }
+        if self.limits.max_file_size == 0 {
+            return Err(ConfigError("max_file_size must be at least 1".to_string()));
+        }
+        if !(1..=100).contains(&self.limits.history_limit) {
+            return Err(ConfigError(
+                "history_limit must be between 1 and 100".to_string(),
+            ));
+        }
+        if self.limits.outbound_queue == 0 {
+            return Err(ConfigError("outbound_queue must be at least 1".to_string()));
+        }
+        for (scope, rate) in [("user", self.flood.user), ("channel", self.flood.channel)] {
+            let Rate {
+                messages_per_sec,
+                bytes_per_sec,
+                burst_secs,
+            } = rate;
+            if [messages_per_sec, bytes_per_sec, burst_secs]
+                .iter()
+                .any(|value| !value.is_finite() || *value <= 0.0)
+            {
+                return Err(ConfigError(format!(
+                    "flood.{scope} rates and burst must be positive"
+                )));
+            }
+        }
+        if self.flood.mute.is_zero() || self.flood.mute > MAX_FLOOD_MUTE {
+            return Err(ConfigError(format!(
+                "flood mute_secs must be between 1 and {}",
+                MAX_FLOOD_MUTE.as_secs()
+            )));
+        }
+        if let Err(e) = self.log.filter() {
+            return Err(ConfigError(format!(
+                "invalid log level {:?}: {e}",
+                self.log.level
+            )));
+        }
+        Ok(())
+    }
+}
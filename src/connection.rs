@@ -0,0 +1,383 @@
+use tokio::io::{
+    self, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use crate::protocol::{Event, Frame, HELLO_OK, MAX_FRAME_LEN, MAX_LINE_LEN, Mode, PING, PONG};
+use crate::users::User;
+
+/// Read half of a client connection, either plain TCP or TLS.
+pub type ReadStream = Box<dyn AsyncRead + Send + Unpin>;
+/// Write half of a client connection, either plain TCP or TLS.
+pub type WriteStream = Box<dyn AsyncWrite + Send + Unpin>;
+
+#[derive(Debug)]
+pub struct Input {
+    pub id: Option<u64>,
+    pub text: String,
+}
+
+pub struct FrameReader {
+    reader: BufReader<ReadStream>,
+    mode: Mode,
+}
+
+pub struct FrameWriter {
+    writer: WriteStream,
+    mode: Mode,
+}
+
+impl FrameReader {
+    pub fn new(reader: ReadStream) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            mode: Mode::Text,
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Reads the next line (text mode) or `command` frame (framed mode).
+    /// `ping` and `pong` frames come back as their text forms. Returns
+    /// `None` once the peer has closed the connection.
+    ///
+    /// Input longer than `MAX_LINE_LEN` is discarded and reported as an
+    /// `InvalidInput` error; the connection can carry on reading after it.
+    pub async fn read_input(&mut self) -> io::Result<Option<Input>> {
+        match self.mode {
+            Mode::Text => {
+                let Some(line) = self.read_line().await? else {
+                    return Ok(None);
+                };
+                Ok(Some(Input {
+                    id: None,
+                    text: line.trim().to_string(),
+                }))
+            }
+            Mode::Framed => match self.read_frame().await? {
+                None => Ok(None),
+                Some(Frame {
+                    event: Event::Command { text },
+                    ..
+                }) if text.len() > MAX_LINE_LEN => Err(line_too_long()),
+                Some(Frame {
+                    id,
+                    event: Event::Command { text },
+                    ..
+                }) => Ok(Some(Input {
+                    id,
+                    text: text.trim().to_string(),
+                })),
+                Some(Frame {
+                    id,
+                    event: Event::Ping,
+                    ..
+                }) => Ok(Some(Input {
+                    id,
+                    text: PING.to_string(),
+                })),
+                Some(Frame {
+                    id,
+                    event: Event::Pong,
+                    ..
+                }) => Ok(Some(Input {
+                    id,
+                    text: PONG.to_string(),
+                })),
+                Some(frame) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected frame from client: {:?}", frame.event),
+                )),
+            },
+        }
+    }
+
+    /// Reads up to `max` bytes of an upload: raw bytes in text mode or one
+    /// `upload_chunk` frame in framed mode. Keepalives may arrive between
+    /// chunks, so pongs are skipped and pings are answered through `user`.
+    pub async fn read_chunk(&mut self, max: usize, user: &User) -> io::Result<Vec<u8>> {
+        if self.mode == Mode::Text {
+            let mut buf = vec![0u8; max];
+            let n = self.reader.read(&mut buf).await?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            buf.truncate(n);
+            return Ok(buf);
+        }
+        loop {
+            match self.read_frame().await? {
+                Some(Frame {
+                    event: Event::UploadChunk { data },
+                    ..
+                }) => {
+                    let chunk = BASE64
+                        .decode(data)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    if chunk.is_empty() || chunk.len() > max {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "upload chunk exceeds the declared file size",
+                        ));
+                    }
+                    return Ok(chunk);
+                }
+                Some(Frame {
+                    event: Event::Pong, ..
+                }) => {}
+                Some(Frame {
+                    id,
+                    event: Event::Ping,
+                    ..
+                }) => user.reply(id, Event::Pong).await?,
+                Some(frame) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("expected upload chunk, got {:?}", frame.event),
+                    ));
+                }
+                None => return Err(io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+
+    /// Reads one line of at most `MAX_LINE_LEN` bytes. A longer line is
+    /// skipped up to its newline so the next read starts on a fresh line.
+    async fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = Vec::new();
+        let limit = MAX_LINE_LEN as u64 + 1;
+        if (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut line)
+            .await?
+            == 0
+        {
+            return Ok(None);
+        }
+        if line.len() > MAX_LINE_LEN && !line.ends_with(b"\n") {
+            loop {
+                line.clear();
+                let n = (&mut self.reader)
+                    .take(limit)
+                    .read_until(b'\n', &mut line)
+                    .await?;
+                if n == 0 || line.ends_with(b"\n") {
+                    break;
+                }
+            }
+            return Err(line_too_long());
+        }
+        String::from_utf8(line)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn read_frame(&mut self) -> io::Result<Option<Frame>> {
+        let len = match self.reader.read_u32().await {
+            Ok(len) => len as usize,
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if len > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {len} bytes exceeds maximum length"),
+            ));
+        }
+        let mut body = vec![0u8; len];
+        self.reader.read_exact(&mut body).await?;
+        Frame::decode(&body).map(Some)
+    }
+}
+
+fn line_too_long() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("Input is longer than {MAX_LINE_LEN} bytes and was ignored"),
+    )
+}
+
+impl FrameWriter {
+    pub fn new(writer: WriteStream) -> Self {
+        Self {
+            writer,
+            mode: Mode::Text,
+        }
+    }
+
+    pub async fn write_event(&mut self, id: Option<u64>, event: Event) -> io::Result<()> {
+        match self.mode {
+            Mode::Text => {
+                if let Some(text) = event.to_text() {
+                    self.writer
+                        .write_all(format!("{text}\n").as_bytes())
+                        .await?;
+                }
+            }
+            Mode::Framed => {
+                let frame = Frame::new(id, event).encode()?;
+                self.writer.write_all(&frame).await?;
+            }
+        }
+        self.writer.flush().await
+    }
+
+    pub async fn write_raw(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes).await
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        self.writer.flush().await?;
+        self.writer.shutdown().await
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+}
+
+/// Switches both halves of a connection to the framed protocol after the
+/// client sent the `HELLO` line.
+pub async fn upgrade(reader: &mut FrameReader, writer: &mut FrameWriter) -> io::Result<()> {
+    writer
+        .writer
+        .write_all(format!("{HELLO_OK}\n").as_bytes())
+        .await?;
+    writer.writer.flush().await?;
+    reader.mode = Mode::Framed;
+    writer.mode = Mode::Framed;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::Role;
+    use std::time::Duration;
+
+    fn framed_reader(bytes: Vec<u8>) -> FrameReader {
+        let mut reader = FrameReader::new(Box::new(std::io::Cursor::new(bytes)));
+        reader.mode = Mode::Framed;
+        reader
+    }
+
+    /// A framed session whose output can be read back from the returned
+    /// end of the pipe.
+    async fn framed_user() -> (User, FrameReader) {
+        let (client, server) = io::duplex(64 * 1024);
+        let mut writer = FrameWriter::new(Box::new(server));
+        writer.mode = Mode::Framed;
+        let (user, _control) = User::from_stream(
+            writer,
+            "alice",
+            Role::User,
+            "Global",
+            Duration::from_secs(3600),
+            16,
+        )
+        .await
+        .unwrap();
+        let mut output = FrameReader::new(Box::new(client));
+        output.mode = Mode::Framed;
+        (user, output)
+    }
+
+    fn chunk_frame(id: Option<u64>, data: &[u8]) -> Vec<u8> {
+        Frame::new(
+            id,
+            Event::UploadChunk {
+                data: BASE64.encode(data),
+            },
+        )
+        .encode()
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn reads_back_an_encoded_command() {
+        let frame = Frame::new(
+            Some(3),
+            Event::Command {
+                text: " /list ".to_string(),
+            },
+        );
+        let mut reader = framed_reader(frame.encode().unwrap());
+
+        let input = reader.read_input().await.unwrap().unwrap();
+        assert_eq!(input.id, Some(3));
+        assert_eq!(input.text, "/list");
+        assert!(reader.read_input().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn rejects_length_prefix_over_the_cap() {
+        let len = (MAX_FRAME_LEN as u32 + 1).to_be_bytes();
+        let mut reader = framed_reader(len.to_vec());
+
+        let err = reader.read_input().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn skips_long_lines_and_keeps_reading() {
+        let mut bytes = vec![b'x'; MAX_LINE_LEN + 10];
+        bytes.extend_from_slice(b"\n/list\n");
+        let mut reader = FrameReader::new(Box::new(std::io::Cursor::new(bytes)));
+
+        let err = reader.read_input().await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let input = reader.read_input().await.unwrap().unwrap();
+        assert_eq!(input.text, "/list");
+    }
+
+    #[tokio::test]
+    async fn skips_pongs_between_upload_chunks() {
+        let (user, _output) = framed_user().await;
+        let mut bytes = chunk_frame(Some(1), b"first");
+        bytes.extend(Frame::new(None, Event::Pong).encode().unwrap());
+        bytes.extend(chunk_frame(Some(2), b"second"));
+        let mut reader = framed_reader(bytes);
+
+        assert_eq!(reader.read_chunk(64, &user).await.unwrap(), b"first");
+        assert_eq!(reader.read_chunk(64, &user).await.unwrap(), b"second");
+    }
+
+    #[tokio::test]
+    async fn answers_pings_during_an_upload() {
+        let (user, mut output) = framed_user().await;
+        let mut bytes = Frame::new(Some(7), Event::Ping).encode().unwrap();
+        bytes.extend(chunk_frame(Some(8), b"data"));
+        let mut reader = framed_reader(bytes);
+
+        assert_eq!(reader.read_chunk(64, &user).await.unwrap(), b"data");
+        user.close();
+        user.closed().await;
+
+        let mut pongs = Vec::new();
+        while let Some(frame) = output.read_frame().await.unwrap() {
+            if matches!(frame.event, Event::Pong) {
+                pongs.push(frame.id);
+            }
+        }
+        assert_eq!(pongs, vec![Some(7)]);
+    }
+
+    #[tokio::test]
+    async fn rejects_other_frames_during_an_upload() {
+        let (user, _output) = framed_user().await;
+        let frame = Frame::new(
+            None,
+            Event::Command {
+                text: "/list".to_string(),
+            },
+        );
+        let mut reader = framed_reader(frame.encode().unwrap());
+
+        let err = reader.read_chunk(64, &user).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
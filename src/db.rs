@@ -1,5 +1,12 @@
 use sqlx::PgPool;
-use crate::models::User;
+use chrono::{DateTime, Utc};
+use tracing::info;
+use crate::files::StoredFile;
+use crate::models::{
+    Channel, DirectMessage, FileMessage, Message, Role, Sanction, SanctionKind, SearchResult,
+    User,
+};
+use crate::telemetry::QueryTimer;
 
 #[derive(Debug, Clone)]
 pub struct UserDb {
@@ -16,10 +23,14 @@ impl UserDb {
         username: String,
         password_hash: &str,
     ) -> Result<User, sqlx::Error> {
+        let _timer = QueryTimer::start("create_user");
         let user = sqlx::query_as::<_, User>(
             r#"
-            INSERT INTO users (username, password_hash)
-            VALUES ($1, $2)
+            INSERT INTO users (username, password_hash, role)
+            SELECT $1, $2, CASE
+                WHEN EXISTS (SELECT 1 FROM users) THEN 'user'::user_role
+                ELSE 'owner'::user_role
+            END
             RETURNING *
             "#,
         )
@@ -28,7 +39,7 @@ impl UserDb {
         .fetch_one(&self.pool)
         .await?;
 
-        println!("INFO: Created user: {}", user.username);
+        info!(user = %user.username, id = user.id, "created user");
         Ok(user)
     }
 
@@ -36,9 +47,10 @@ impl UserDb {
         &self,
         username: &str,
     ) -> Result<Option<User>, sqlx::Error> {
+        let _timer = QueryTimer::start("find_by_username");
         let user = sqlx::query_as::<_, User>(
             r#"
-            SELECT id, username, password_hash, created_at
+            SELECT id, username, password_hash, role, created_at, locked_until
             FROM users
             WHERE username = $1
             "#,
@@ -50,17 +62,694 @@ impl UserDb {
         Ok(user)
     }
 
-    pub async fn get_all_users(&self) -> Result<Vec<User>, sqlx::Error> {
-        let users = sqlx::query_as::<_, User>(
+    pub async fn find_role(&self, username: &str) -> Result<Option<Role>, sqlx::Error> {
+        let _timer = QueryTimer::start("find_role");
+        sqlx::query_scalar::<_, Role>(
             r#"
-            SELECT id, username, password_hash, created_at
+            SELECT role
             FROM users
-            ORDER BY created_at DESC
-            "#
+            WHERE username = $1
+            "#,
+        )
+        .bind(username)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Returns `false` when no such user exists.
+    pub async fn set_role(&self, username: &str, role: Role) -> Result<bool, sqlx::Error> {
+        let _timer = QueryTimer::start("set_role");
+        let result = sqlx::query(
+            r#"
+            UPDATE users
+            SET role = $2
+            WHERE username = $1
+            "#,
+        )
+        .bind(username)
+        .bind(role)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Counts a failed login. From the `threshold`-th failure on, the
+    /// account is locked for `base_secs`, doubling with every further
+    /// failure up to `max_secs`. Returns the new lock, if any.
+    pub async fn record_failed_login(
+        &self,
+        username: &str,
+        threshold: i32,
+        base_secs: f64,
+        max_secs: f64,
+    ) -> Result<Option<DateTime<Utc>>, sqlx::Error> {
+        let _timer = QueryTimer::start("record_failed_login");
+        let locked_until = sqlx::query_scalar::<_, Option<DateTime<Utc>>>(
+            r#"
+            UPDATE users
+            SET failed_logins = failed_logins + 1,
+                locked_until = CASE
+                    WHEN failed_logins + 1 >= $2 THEN now() + make_interval(
+                        secs => LEAST($3 * power(2, failed_logins + 1 - $2), $4)
+                    )
+                    ELSE locked_until
+                END
+            WHERE username = $1
+            RETURNING locked_until
+            "#,
+        )
+        .bind(username)
+        .bind(threshold)
+        .bind(base_secs)
+        .bind(max_secs)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(locked_until.flatten())
+    }
+
+    pub async fn reset_failed_logins(&self, username: &str) -> Result<(), sqlx::Error> {
+        let _timer = QueryTimer::start("reset_failed_logins");
+        sqlx::query(
+            r#"
+            UPDATE users
+            SET failed_logins = 0, locked_until = NULL
+            WHERE username = $1 AND (failed_logins > 0 OR locked_until IS NOT NULL)
+            "#,
+        )
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MessageDb {
+    pool: PgPool,
+}
+
+impl MessageDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_message(
+        &self,
+        channel: &str,
+        sender: &str,
+        text: &str,
+    ) -> Result<Message, sqlx::Error> {
+        let message = sqlx::query_as::<_, Message>(
+            r#"
+            WITH channel AS (
+                INSERT INTO channels (name)
+                VALUES ($1)
+                ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+                RETURNING id, name
+            ),
+            inserted AS (
+                INSERT INTO messages (channel_id, sender_id, text)
+                SELECT channel.id, users.id, $3
+                FROM channel, users
+                WHERE users.username = $2
+                RETURNING id, text, created_at
+            )
+            SELECT inserted.id, channel.name AS channel, $2 AS sender,
+                   inserted.text, inserted.created_at
+            FROM inserted, channel
+            "#,
+        )
+        .bind(channel)
+        .bind(sender)
+        .bind(text)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    pub async fn recent_messages(
+        &self,
+        channel: &str,
+        limit: i64,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        self.channel_history(channel, None, limit).await
+    }
+
+    /// Pages backwards through a channel, returning up to `limit` messages
+    /// older than `before`, oldest first.
+    pub async fn channel_history(
+        &self,
+        channel: &str,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<Message>, sqlx::Error> {
+        let mut messages = sqlx::query_as::<_, Message>(
+            r#"
+            SELECT m.id, c.name AS channel, u.username AS sender, m.text, m.created_at
+            FROM messages m
+            JOIN channels c ON c.id = m.channel_id
+            JOIN users u ON u.id = m.sender_id
+            WHERE c.name = $1 AND ($2::BIGINT IS NULL OR m.id < $2)
+            ORDER BY m.id DESC
+            LIMIT $3
+            "#,
+        )
+        .bind(channel)
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Stores a direct message in the conversation between sender and
+    /// recipient. Undelivered messages are handed out on the recipient's next
+    /// login. Returns `None` when the recipient does not exist.
+    pub async fn create_direct_message(
+        &self,
+        sender: &str,
+        recipient: &str,
+        text: &str,
+        delivered: bool,
+    ) -> Result<Option<DirectMessage>, sqlx::Error> {
+        let message = sqlx::query_as::<_, DirectMessage>(
+            r#"
+            WITH participants AS (
+                SELECT s.id AS sender_id, r.id AS recipient_id
+                FROM users s, users r
+                WHERE s.username = $1 AND r.username = $2
+            ),
+            conversation AS (
+                INSERT INTO conversations (user_low, user_high)
+                SELECT LEAST(sender_id, recipient_id), GREATEST(sender_id, recipient_id)
+                FROM participants
+                ON CONFLICT (user_low, user_high) DO UPDATE SET user_low = EXCLUDED.user_low
+                RETURNING id
+            )
+            INSERT INTO messages (sender_id, recipient_id, conversation_id, text, delivered_at)
+            SELECT p.sender_id, p.recipient_id, c.id, $3, CASE WHEN $4 THEN now() END
+            FROM participants p, conversation c
+            RETURNING id, $1 AS sender, $2 AS recipient, text, created_at
+            "#,
+        )
+        .bind(sender)
+        .bind(recipient)
+        .bind(text)
+        .bind(delivered)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    /// Pages backwards through the conversation between two users, returning
+    /// up to `limit` messages older than `before`, oldest first.
+    pub async fn conversation_history(
+        &self,
+        username: &str,
+        other: &str,
+        before: Option<i64>,
+        limit: i64,
+    ) -> Result<Vec<DirectMessage>, sqlx::Error> {
+        let mut messages = sqlx::query_as::<_, DirectMessage>(
+            r#"
+            SELECT m.id, s.username AS sender, r.username AS recipient,
+                   COALESCE(m.text, '') AS text, m.created_at
+            FROM users a
+            JOIN users b ON b.username = $2
+            JOIN conversations c
+              ON c.user_low = LEAST(a.id, b.id) AND c.user_high = GREATEST(a.id, b.id)
+            JOIN messages m ON m.conversation_id = c.id
+            JOIN users s ON s.id = m.sender_id
+            JOIN users r ON r.id = m.recipient_id
+            WHERE a.username = $1 AND ($3::BIGINT IS NULL OR m.id < $3)
+            ORDER BY m.id DESC
+            LIMIT $4
+            "#,
+        )
+        .bind(username)
+        .bind(other)
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// The recipient's undelivered direct messages, oldest first. They stay
+    /// undelivered until passed to `mark_delivered`.
+    pub async fn undelivered_messages(
+        &self,
+        recipient: &str,
+    ) -> Result<Vec<DirectMessage>, sqlx::Error> {
+        let messages = sqlx::query_as::<_, DirectMessage>(
+            r#"
+            SELECT m.id, s.username AS sender, $1 AS recipient,
+                   COALESCE(m.text, '') AS text, m.created_at
+            FROM messages m
+            JOIN users r ON r.id = m.recipient_id
+            JOIN users s ON s.id = m.sender_id
+            WHERE r.username = $1
+              AND m.delivered_at IS NULL AND m.file_url IS NULL
+            ORDER BY m.created_at, m.id
+            "#,
+        )
+        .bind(recipient)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(messages)
+    }
+
+    pub async fn mark_delivered(&self, ids: &[i64]) -> Result<(), sqlx::Error> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        sqlx::query(
+            r#"
+            UPDATE messages
+            SET delivered_at = now()
+            WHERE id = ANY($1) AND delivered_at IS NULL
+            "#,
+        )
+        .bind(ids)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Full-text search over channel messages and the requester's own
+    /// conversations, skipping channels the requester is banned from.
+    pub async fn search_messages(
+        &self,
+        requester: &str,
+        query: &str,
+        channel: Option<&str>,
+        sender: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<SearchResult>, sqlx::Error> {
+        let results = sqlx::query_as::<_, SearchResult>(
+            r#"
+            SELECT m.id, c.name AS channel, s.username AS sender, r.username AS recipient,
+                   COALESCE(m.text, '') AS text, m.created_at
+            FROM messages m
+            JOIN users me ON me.username = $1
+            JOIN users s ON s.id = m.sender_id
+            LEFT JOIN channels c ON c.id = m.channel_id
+            LEFT JOIN users r ON r.id = m.recipient_id
+            WHERE m.search_vector @@ websearch_to_tsquery('english', $2)
+              AND m.file_url IS NULL
+              AND (
+                  (m.channel_id IS NOT NULL AND NOT EXISTS (
+                      SELECT 1
+                      FROM channel_sanctions b
+                      WHERE b.channel_id = m.channel_id AND b.user_id = me.id
+                        AND b.kind = 'ban'
+                        AND (b.expires_at IS NULL OR b.expires_at > now())
+                  ))
+                  OR (m.conversation_id IS NOT NULL AND me.id IN (m.sender_id, m.recipient_id))
+              )
+              AND ($3::TEXT IS NULL OR c.name = $3)
+              AND ($4::TEXT IS NULL OR s.username = $4)
+            ORDER BY m.created_at DESC, m.id DESC
+            LIMIT $5
+            "#,
+        )
+        .bind(requester)
+        .bind(query)
+        .bind(channel)
+        .bind(sender)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(results)
+    }
+
+    pub async fn create_file_message(
+        &self,
+        sender: &str,
+        recipient: &str,
+        name: &str,
+        file: &StoredFile,
+    ) -> Result<FileMessage, sqlx::Error> {
+        let message = sqlx::query_as::<_, FileMessage>(
+            r#"
+            WITH inserted AS (
+                INSERT INTO messages
+                    (sender_id, recipient_id, text, file_url, file_type, file_size, file_sha256)
+                SELECT s.id, r.id, $3, $4, $5, $6, $7
+                FROM users s, users r
+                WHERE s.username = $1 AND r.username = $2
+                RETURNING id, text, file_url, file_type, file_size, file_sha256, created_at
+            )
+            SELECT id, $1 AS sender, $2 AS recipient, text AS name,
+                   file_url, file_type, file_size, file_sha256, created_at
+            FROM inserted
+            "#,
+        )
+        .bind(sender)
+        .bind(recipient)
+        .bind(name)
+        .bind(&file.url)
+        .bind(&file.file_type)
+        .bind(file.size)
+        .bind(&file.sha256)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(message)
+    }
+
+    pub async fn find_file_for_recipient(
+        &self,
+        id: i64,
+        recipient: &str,
+    ) -> Result<Option<FileMessage>, sqlx::Error> {
+        let file = sqlx::query_as::<_, FileMessage>(
+            r#"
+            SELECT m.id, s.username AS sender, r.username AS recipient, m.text AS name,
+                   m.file_url, m.file_type, m.file_size, m.file_sha256, m.created_at
+            FROM messages m
+            JOIN users s ON s.id = m.sender_id
+            JOIN users r ON r.id = m.recipient_id
+            WHERE m.id = $1 AND r.username = $2 AND m.file_url IS NOT NULL
+            "#,
+        )
+        .bind(id)
+        .bind(recipient)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(file)
+    }
+
+    pub async fn files_for_recipient(
+        &self,
+        recipient: &str,
+        limit: i64,
+    ) -> Result<Vec<FileMessage>, sqlx::Error> {
+        let files = sqlx::query_as::<_, FileMessage>(
+            r#"
+            SELECT m.id, s.username AS sender, r.username AS recipient, m.text AS name,
+                   m.file_url, m.file_type, m.file_size, m.file_sha256, m.created_at
+            FROM messages m
+            JOIN users s ON s.id = m.sender_id
+            JOIN users r ON r.id = m.recipient_id
+            WHERE r.username = $1 AND m.file_url IS NOT NULL
+            ORDER BY m.created_at DESC, m.id DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(recipient)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(files)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelDb {
+    pool: PgPool,
+}
+
+impl ChannelDb {
+    pub fn new(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
+    pub async fn create_channel(
+        &self,
+        name: &str,
+        creator: Option<&str>,
+        topic: Option<&str>,
+    ) -> Result<Option<Channel>, sqlx::Error> {
+        let channel = sqlx::query_as::<_, Channel>(
+            r#"
+            WITH inserted AS (
+                INSERT INTO channels (name, topic, created_by)
+                VALUES ($1, $3, (SELECT id FROM users WHERE username = $2))
+                ON CONFLICT (name) DO NOTHING
+                RETURNING id, name, topic, created_at
+            )
+            SELECT id, name, topic, $2 AS created_by, created_at
+            FROM inserted
+            "#,
+        )
+        .bind(name)
+        .bind(creator)
+        .bind(topic)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        if let Some(channel) = &channel {
+            info!(channel = %channel.name, id = channel.id, "created channel");
+        }
+        Ok(channel)
+    }
+
+    /// Returns the channel named `name`, creating it first if needed.
+    /// Channels created without a `creator` have no owner.
+    pub async fn find_or_create(
+        &self,
+        name: &str,
+        creator: Option<&str>,
+    ) -> Result<Channel, sqlx::Error> {
+        if let Some(channel) = self.create_channel(name, creator, None).await? {
+            return Ok(channel);
+        }
+        self.find_by_name(name)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)
+    }
+
+    pub async fn find_by_name(&self, name: &str) -> Result<Option<Channel>, sqlx::Error> {
+        let channel = sqlx::query_as::<_, Channel>(
+            r#"
+            SELECT c.id, c.name, c.topic, u.username AS created_by, c.created_at
+            FROM channels c
+            LEFT JOIN users u ON u.id = c.created_by
+            WHERE c.name = $1
+            "#,
+        )
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(channel)
+    }
+
+    pub async fn get_all_channels(&self) -> Result<Vec<Channel>, sqlx::Error> {
+        let channels = sqlx::query_as::<_, Channel>(
+            r#"
+            SELECT c.id, c.name, c.topic, u.username AS created_by, c.created_at
+            FROM channels c
+            LEFT JOIN users u ON u.id = c.created_by
+            ORDER BY c.name
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(channels)
+    }
+
+    pub async fn set_topic(&self, name: &str, topic: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            UPDATE channels
+            SET topic = $2
+            WHERE name = $1
+            "#,
+        )
+        .bind(name)
+        .bind(topic)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Channel creators are implicit operators of their channel.
+    pub async fn is_operator(&self, channel: &str, username: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar::<_, bool>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1
+                FROM channels c
+                JOIN users u ON u.username = $2
+                LEFT JOIN channel_operators o ON o.channel_id = c.id AND o.user_id = u.id
+                WHERE c.name = $1 AND (c.created_by = u.id OR o.user_id IS NOT NULL)
+            )
+            "#,
+        )
+        .bind(channel)
+        .bind(username)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    /// The channel creator and all operators.
+    pub async fn operators(&self, channel: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT u.username
+            FROM channels c
+            JOIN users u ON u.id = c.created_by
+            WHERE c.name = $1
+            UNION
+            SELECT u.username
+            FROM channels c
+            JOIN channel_operators o ON o.channel_id = c.id
+            JOIN users u ON u.id = o.user_id
+            WHERE c.name = $1
+            "#,
+        )
+        .bind(channel)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn add_operator(
+        &self,
+        channel: &str,
+        username: &str,
+        granted_by: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO channel_operators (channel_id, user_id, granted_by)
+            SELECT c.id, u.id, (SELECT id FROM users WHERE username = $3)
+            FROM channels c, users u
+            WHERE c.name = $1 AND u.username = $2
+            ON CONFLICT (channel_id, user_id) DO UPDATE SET granted_by = EXCLUDED.granted_by
+            "#,
+        )
+        .bind(channel)
+        .bind(username)
+        .bind(granted_by)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn remove_operator(
+        &self,
+        channel: &str,
+        username: &str,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM channel_operators o
+            USING channels c, users u
+            WHERE o.channel_id = c.id AND o.user_id = u.id
+              AND c.name = $1 AND u.username = $2
+            "#,
+        )
+        .bind(channel)
+        .bind(username)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Adds or replaces a ban or mute. Returns `false` when the channel or
+    /// user does not exist.
+    pub async fn add_sanction(
+        &self,
+        channel: &str,
+        username: &str,
+        kind: SanctionKind,
+        reason: Option<&str>,
+        created_by: Option<&str>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO channel_sanctions (channel_id, user_id, kind, reason, created_by, expires_at)
+            SELECT c.id, u.id, $3, $4, (SELECT id FROM users WHERE username = $5), $6
+            FROM channels c, users u
+            WHERE c.name = $1 AND u.username = $2
+            ON CONFLICT (channel_id, user_id, kind) DO UPDATE
+            SET reason = EXCLUDED.reason,
+                created_by = EXCLUDED.created_by,
+                expires_at = EXCLUDED.expires_at,
+                created_at = now()
+            "#,
+        )
+        .bind(channel)
+        .bind(username)
+        .bind(kind)
+        .bind(reason)
+        .bind(created_by)
+        .bind(expires_at)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn remove_sanction(
+        &self,
+        channel: &str,
+        username: &str,
+        kind: SanctionKind,
+    ) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM channel_sanctions s
+            USING channels c, users u
+            WHERE s.channel_id = c.id AND s.user_id = u.id
+              AND c.name = $1 AND u.username = $2 AND s.kind = $3
+              AND (s.expires_at IS NULL OR s.expires_at > now())
+            "#,
         )
+        .bind(channel)
+        .bind(username)
+        .bind(kind)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Returns the user's unexpired sanctions in a channel, bans first.
+    pub async fn active_sanctions(
+        &self,
+        channel: &str,
+        username: &str,
+    ) -> Result<Vec<Sanction>, sqlx::Error> {
+        let sanctions = sqlx::query_as::<_, Sanction>(
+            r#"
+            SELECT c.name AS channel, u.username, s.kind, s.reason,
+                   b.username AS created_by, s.expires_at
+            FROM channel_sanctions s
+            JOIN channels c ON c.id = s.channel_id
+            JOIN users u ON u.id = s.user_id
+            LEFT JOIN users b ON b.id = s.created_by
+            WHERE c.name = $1 AND u.username = $2
+              AND (s.expires_at IS NULL OR s.expires_at > now())
+            ORDER BY s.kind
+            "#,
+        )
+        .bind(channel)
+        .bind(username)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(users)
+        Ok(sanctions)
     }
 }
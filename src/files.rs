@@ -0,0 +1,178 @@
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncWriteExt};
+use uuid::Uuid;
+
+use crate::connection::FrameReader;
+use crate::telemetry;
+use crate::users::User;
+
+pub const CHUNK_SIZE: usize = 8192;
+
+const URL_PREFIX: &str = "/files/";
+
+#[derive(Debug, Clone)]
+pub struct FileUpload {
+    pub target: String,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct StoredFile {
+    pub url: String,
+    pub file_type: String,
+    pub size: i64,
+    pub sha256: String,
+}
+
+#[derive(Debug)]
+pub enum UploadOutcome {
+    Stored(StoredFile),
+    ChecksumMismatch,
+}
+
+/// An upload that is still being written. The file is deleted on drop
+/// unless `keep` was called.
+struct PartialFile(Option<PathBuf>);
+
+impl PartialFile {
+    fn keep(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl FileUpload {
+    /// Parses the `<name> <size> <sha256>` part of `/send`. The name may
+    /// contain spaces, so the size and checksum are taken from the end.
+    pub fn parse(target: &str, spec: &str, max_size: u64) -> Result<Self, String> {
+        let parts: Vec<&str> = spec.rsplitn(3, ' ').collect();
+        let [sha256, size, name] = parts.as_slice() else {
+            return Err("Usage: /send <user> <name> <size> <sha256>".to_string());
+        };
+
+        let name = Path::new(name)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or_default()
+            .to_string();
+        if name.is_empty() {
+            return Err("File name must not be empty".to_string());
+        }
+
+        let size: u64 = size
+            .parse()
+            .map_err(|_| format!("Invalid file size: {size}"))?;
+        if size == 0 || size > max_size {
+            return Err(format!("File size must be between 1 and {max_size} bytes"));
+        }
+
+        let sha256 = sha256.to_ascii_lowercase();
+        if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err("Checksum must be a hex encoded SHA-256 digest".to_string());
+        }
+
+        Ok(Self {
+            target: target.to_string(),
+            name,
+            size,
+            sha256,
+        })
+    }
+
+    pub fn file_type(&self) -> &'static str {
+        let extension = Path::new(&self.name)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        match extension.as_str() {
+            "txt" | "log" => "text/plain",
+            "md" => "text/markdown",
+            "json" => "application/json",
+            "pdf" => "application/pdf",
+            "zip" => "application/zip",
+            "png" => "image/png",
+            "jpg" | "jpeg" => "image/jpeg",
+            "gif" => "image/gif",
+            _ => "application/octet-stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    dir: PathBuf,
+    pub max_size: u64,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>, max_size: u64) -> Self {
+        Self {
+            dir: dir.into(),
+            max_size,
+        }
+    }
+
+    /// Reads exactly `upload.size` bytes from the client into the store and
+    /// checks them against the declared checksum. `user` answers keepalive
+    /// pings that arrive between framed chunks.
+    pub async fn receive(
+        &self,
+        reader: &mut FrameReader,
+        upload: &FileUpload,
+        user: &User,
+    ) -> io::Result<UploadOutcome> {
+        fs::create_dir_all(&self.dir).await?;
+
+        let key = Uuid::new_v4();
+        let path = self.dir.join(key.to_string());
+        let mut file = File::create(&path).await?;
+        // Removes the partial file on every early return, read and write
+        // errors alike, and when the upload is abandoned mid-way.
+        let partial = PartialFile(Some(path));
+        let mut hasher = Sha256::new();
+        let mut remaining = upload.size;
+
+        while remaining > 0 {
+            let max = remaining.min(CHUNK_SIZE as u64) as usize;
+            let chunk = reader.read_chunk(max, user).await?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+            remaining -= chunk.len() as u64;
+            telemetry::file_bytes("upload", chunk.len() as u64);
+        }
+        file.flush().await?;
+
+        let sha256 = format!("{:x}", hasher.finalize());
+        if sha256 != upload.sha256 {
+            return Ok(UploadOutcome::ChecksumMismatch);
+        }
+        partial.keep();
+
+        Ok(UploadOutcome::Stored(StoredFile {
+            url: format!("{URL_PREFIX}{key}"),
+            file_type: upload.file_type().to_string(),
+            size: upload.size as i64,
+            sha256,
+        }))
+    }
+
+    pub async fn open(&self, url: &str) -> io::Result<File> {
+        let key = url
+            .strip_prefix(URL_PREFIX)
+            .and_then(|key| Uuid::parse_str(key).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "invalid file url"))?;
+        File::open(self.dir.join(key.to_string())).await
+    }
+}
@@ -0,0 +1,297 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Warnings older than this are forgotten.
+const STRIKE_WINDOW: Duration = Duration::from_secs(60);
+/// Idle buckets are dropped once a map grows past this size.
+const PRUNE_AT: usize = 1024;
+
+/// Sustained rate and burst size for one scope. A bucket holds
+/// `burst_secs` worth of its rate.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rate {
+    pub messages_per_sec: f64,
+    pub bytes_per_sec: f64,
+    pub burst_secs: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct FloodLimits {
+    pub user: Rate,
+    pub channel: Rate,
+    /// Warnings within a minute before a sender is muted.
+    pub warnings: u32,
+    pub mute: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allowed,
+    /// The sender is over their own limit. `mute` is set once they have
+    /// used up their warnings.
+    UserLimited {
+        mute: bool,
+    },
+    /// The channel as a whole is over its limit.
+    ChannelLimited,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    capacity: f64,
+    rate: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, burst_secs: f64, now: Instant) -> Self {
+        let capacity = (rate * burst_secs).max(1.0);
+        Self {
+            capacity,
+            rate,
+            tokens: capacity,
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Anything larger than the whole bucket only needs a full bucket.
+    fn cost(&self, amount: f64) -> f64 {
+        amount.min(self.capacity)
+    }
+
+    fn has(&self, amount: f64) -> bool {
+        self.tokens >= self.cost(amount)
+    }
+
+    fn take(&mut self, amount: f64) {
+        self.tokens -= self.cost(amount);
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.capacity
+    }
+}
+
+/// A message bucket and a byte bucket that are always charged together.
+#[derive(Debug)]
+struct Buckets {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl Buckets {
+    fn new(rate: Rate, now: Instant) -> Self {
+        Self {
+            messages: TokenBucket::new(rate.messages_per_sec, rate.burst_secs, now),
+            bytes: TokenBucket::new(rate.bytes_per_sec, rate.burst_secs, now),
+        }
+    }
+
+    fn allows(&mut self, bytes: f64, now: Instant) -> bool {
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        self.messages.has(1.0) && self.bytes.has(bytes)
+    }
+
+    fn take(&mut self, bytes: f64) {
+        self.messages.take(1.0);
+        self.bytes.take(bytes);
+    }
+
+    fn is_idle(&mut self, now: Instant) -> bool {
+        self.messages.refill(now);
+        self.bytes.refill(now);
+        self.messages.is_full() && self.bytes.is_full()
+    }
+}
+
+#[derive(Debug)]
+struct Sender {
+    buckets: Buckets,
+    strikes: u32,
+    last_strike: Instant,
+}
+
+#[derive(Debug, Default)]
+struct FloodState {
+    senders: HashMap<String, Sender>,
+    channels: HashMap<String, Buckets>,
+}
+
+/// Token-bucket limits for chat messages, per sender and per channel,
+/// shared by all connections.
+#[derive(Debug, Clone)]
+pub struct FloodControl {
+    limits: Arc<FloodLimits>,
+    state: Arc<Mutex<FloodState>>,
+}
+
+impl FloodControl {
+    pub fn new(limits: FloodLimits) -> Self {
+        Self {
+            limits: Arc::new(limits),
+            state: Arc::default(),
+        }
+    }
+
+    pub fn mute_duration(&self) -> Duration {
+        self.limits.mute
+    }
+
+    /// Charges a message of `bytes` to its sender and, for channel
+    /// messages, to the channel. Nothing is charged unless both allow it.
+    pub async fn check(&self, username: &str, channel: Option<&str>, bytes: usize) -> Verdict {
+        let now = Instant::now();
+        let bytes = bytes as f64;
+        let mut state = self.state.lock().await;
+        let FloodState { senders, channels } = &mut *state;
+
+        if senders.len() >= PRUNE_AT {
+            senders.retain(|_, sender| !sender.buckets.is_idle(now));
+        }
+        let sender = senders
+            .entry(username.to_string())
+            .or_insert_with(|| Sender {
+                buckets: Buckets::new(self.limits.user, now),
+                strikes: 0,
+                last_strike: now,
+            });
+        if !sender.buckets.allows(bytes, now) {
+            if now.duration_since(sender.last_strike) >= STRIKE_WINDOW {
+                sender.strikes = 0;
+            }
+            sender.strikes += 1;
+            sender.last_strike = now;
+            let mute = sender.strikes > self.limits.warnings;
+            if mute {
+                sender.strikes = 0;
+            }
+            return Verdict::UserLimited { mute };
+        }
+
+        if let Some(channel) = channel {
+            if channels.len() >= PRUNE_AT {
+                channels.retain(|_, buckets| !buckets.is_idle(now));
+            }
+            let buckets = channels
+                .entry(channel.to_string())
+                .or_insert_with(|| Buckets::new(self.limits.channel, now));
+            if !buckets.allows(bytes, now) {
+                return Verdict::ChannelLimited;
+            }
+            buckets.take(bytes);
+        }
+        sender.buckets.take(bytes);
+        Verdict::Allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rate(messages_per_sec: f64, burst_secs: f64) -> Rate {
+        Rate {
+            messages_per_sec,
+            bytes_per_sec: 1_000_000.0,
+            burst_secs,
+        }
+    }
+
+    #[test]
+    fn bucket_refills_at_its_rate_up_to_capacity() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 5.0, start);
+        assert_eq!(bucket.capacity, 10.0);
+
+        bucket.take(10.0);
+        assert!(!bucket.has(1.0));
+
+        bucket.refill(start + Duration::from_millis(500));
+        assert!(bucket.has(1.0));
+        assert!(!bucket.has(2.0));
+
+        bucket.refill(start + Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn oversized_amounts_need_only_a_full_bucket() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, 1.0, start);
+        assert!(bucket.has(1_000.0));
+        bucket.take(1_000.0);
+        assert_eq!(bucket.tokens, 0.0);
+    }
+
+    #[test]
+    fn tiny_rates_still_allow_one_message() {
+        let bucket = TokenBucket::new(0.1, 1.0, Instant::now());
+        assert_eq!(bucket.capacity, 1.0);
+    }
+
+    #[tokio::test]
+    async fn warns_then_mutes_a_flooding_sender() {
+        let flood = FloodControl::new(FloodLimits {
+            user: rate(1.0, 2.0),
+            channel: rate(100.0, 10.0),
+            warnings: 1,
+            mute: Duration::from_secs(60),
+        });
+
+        assert_eq!(
+            flood.check("bob", Some("Global"), 5).await,
+            Verdict::Allowed
+        );
+        assert_eq!(
+            flood.check("bob", Some("Global"), 5).await,
+            Verdict::Allowed
+        );
+        assert_eq!(
+            flood.check("bob", Some("Global"), 5).await,
+            Verdict::UserLimited { mute: false }
+        );
+        assert_eq!(
+            flood.check("bob", Some("Global"), 5).await,
+            Verdict::UserLimited { mute: true }
+        );
+        assert_eq!(
+            flood.check("carol", Some("Global"), 5).await,
+            Verdict::Allowed
+        );
+    }
+
+    #[tokio::test]
+    async fn channel_limit_does_not_charge_the_sender() {
+        let flood = FloodControl::new(FloodLimits {
+            user: rate(1.0, 2.0),
+            channel: rate(1.0, 1.0),
+            warnings: 3,
+            mute: Duration::from_secs(60),
+        });
+
+        assert_eq!(flood.check("bob", Some("busy"), 5).await, Verdict::Allowed);
+        assert_eq!(
+            flood.check("bob", Some("busy"), 5).await,
+            Verdict::ChannelLimited
+        );
+        // The rejected message left bob's own bucket untouched.
+        assert_eq!(flood.check("bob", Some("quiet"), 5).await, Verdict::Allowed);
+        assert_eq!(
+            flood.check("bob", None, 5).await,
+            Verdict::UserLimited { mute: false }
+        );
+    }
+}
@@ -1,46 +1,87 @@
 mod auth;
+mod commands;
+mod config;
+mod connection;
 mod db;
+mod files;
+mod flood;
+mod logging;
 mod messages;
 mod models;
+mod outbox;
+mod presence;
+mod protocol;
 mod server;
+mod state;
+mod telemetry;
+mod tls;
 mod users;
+mod validation;
 
 use dotenvy::dotenv;
 use sqlx::postgres::PgPoolOptions;
-use std::env;
+use std::process::ExitCode;
+use std::sync::Arc;
+use tracing::{error, info};
 
-use crate::db::UserDb;
+use crate::config::Config;
+use crate::db::ChannelDb;
+use crate::files::FileStore;
 use crate::server::start_server;
 
 #[tokio::main]
-async fn main() -> std::io::Result<()> {
-    //db_connection().await;
+async fn main() -> ExitCode {
     dotenv().ok();
 
-    let database_url = env::var("DATABASE_URL").expect("ERROR: DATABASE_URL not set");
+    let config = match Config::load() {
+        Ok(config) => Arc::new(config),
+        Err(e) => {
+            eprintln!("ERROR: invalid configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    logging::init(&config.log);
 
-    let pool = PgPoolOptions::new()
-        .max_connections(5)
-        .connect(&database_url)
+    let pool = match PgPoolOptions::new()
+        .max_connections(config.db_max_connections)
+        .connect(&config.database_url)
         .await
-        .expect("ERROR: failed to connect to Postgres");
+    {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!(error = %e, "failed to connect to Postgres");
+            return ExitCode::FAILURE;
+        }
+    };
 
-    // test query
-    let row: (i32,) = sqlx::query_as("SELECT 1")
-        .fetch_one(&pool)
-        .await
-        .expect("ERROR: failed to run test query");
+    if let Err(e) = sqlx::migrate!("./migrations/migrations").run(&pool).await {
+        error!(error = %e, "failed to run migrations");
+        return ExitCode::FAILURE;
+    }
 
-    let _ = sqlx::migrate!("./migrations").run(&pool).await;
+    // The migrations only seed `Global`. Any other default channel needs its
+    // row before users can set its topic or moderate it.
+    let channel_db = ChannelDb::new(pool.clone());
+    if let Err(e) = channel_db
+        .find_or_create(&config.default_channel, None)
+        .await
+    {
+        error!(channel = %config.default_channel, error = %e, "failed to create default channel");
+        return ExitCode::FAILURE;
+    }
 
-    println!("INFO: database connected successfully, result = {}", row.0);
+    info!("database connected successfully");
 
-    UserDb::new(pool.clone());
+    let files = FileStore::new(config.file_storage_dir.clone(), config.limits.max_file_size);
 
-    println!("INFO: server starting on port :6969");
+    info!(addr = %config.bind_addr, "server starting");
 
-    if let Err(e) = start_server("0.0.0.0:6969", pool).await {
-        eprintln!("ERROR: failed to start server: {}", e);
+    if let Err(e) = start_server(config, files, pool.clone()).await {
+        error!(error = %e, "failed to start server");
+        return ExitCode::FAILURE;
     }
-    Ok(())
+
+    pool.close().await;
+    info!("server stopped");
+    ExitCode::SUCCESS
 }
@@ -1,302 +1,1205 @@
-use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
-use tokio::fs::File;
+use chrono::{TimeDelta, Utc};
+use std::time::Duration;
 use tokio::io;
-use tokio::sync::Mutex;
+use tracing::{debug, error, info, warn};
 
+use crate::commands::{self, Command, Invocation};
+use crate::db::{ChannelDb, UserDb};
+use crate::files::{FileUpload, UploadOutcome};
+use crate::flood::Verdict;
+use crate::models::{Role, Sanction, SanctionKind};
+use crate::protocol::{Event, HistoryEntry, SearchHit};
 use crate::server::ConnectionStatus;
-use crate::users::User;
-
-pub type Users = Arc<Mutex<HashMap<String, User>>>;
-
-#[derive(Debug)]
-pub enum Command {
-    PrivateMessage { target: String, message: String },
-    SendFile { target: String, file_path: String },
-    JoinChannel(String),
-    ListUsers,
-    ListChannels,
-    CloseConnection,
-    KickUser(String),
-    Broadcast(String),
-    ProfileView,
-    ChangeRole,
-    Unknown,
+use crate::state::{ServerState, Users};
+use crate::telemetry;
+use crate::users::{CloseReason, User};
+use crate::validation::{self, ChannelName};
+
+const FILES_LIMIT: i64 = 20;
+const SEARCH_LIMIT: i64 = 20;
+
+/// The user a command runs for and the state it runs against. Built once
+/// per connection and handed to every command handler.
+#[derive(Clone)]
+pub struct Context {
+    pub username: String,
+    pub state: ServerState,
 }
 
-impl Command {
-    pub fn parse(input: String) -> Self {
-        let parts: Vec<&str> = input.splitn(3, ' ').collect();
+impl Context {
+    pub fn new(username: String, state: ServerState) -> Self {
+        Self { username, state }
+    }
 
-        match parts.as_slice() {
-            ["/msg", target, message] => Command::PrivateMessage {
-                target: target.to_string(),
-                message: message.to_string(),
-            },
-            ["/send", target, file_path] => Command::SendFile {
-                target: target.to_string(),
-                file_path: file_path.to_string(),
-            },
-            ["/kick", target] => Command::KickUser(target.to_string()),
-            ["/join", channel] => Command::JoinChannel(channel.to_string()),
-            ["/list"] => Command::ListUsers,
-            ["/channels"] => Command::ListChannels,
-            ["/profile"] => Command::ProfileView,
-            ["/role"] => Command::ChangeRole,
-            ["/close"] => Command::CloseConnection,
-            ["/help"] => Command::Unknown,
-            [""] => Command::Unknown,
+    /// Sends a line to the user running the command.
+    pub async fn reply(&self, message: impl Into<String>) -> io::Result<()> {
+        self.state
+            .send_message(&self.username, message.into())
+            .await
+    }
 
-            _ if input.starts_with('/') => Command::Unknown,
-            _ => Command::Broadcast(input),
-        }
+    pub async fn reply_event(&self, event: Event) -> io::Result<()> {
+        self.state.send_event(&self.username, event).await
+    }
+
+    /// The channel the user is in, or an empty string once they are gone.
+    pub fn channel(&self) -> String {
+        self.state
+            .users
+            .channel_of(&self.username)
+            .unwrap_or_default()
     }
 }
 
 pub struct CommandExecutor;
 
 impl CommandExecutor {
-    pub async fn execute(
-        username: String,
-        input: String,
-        users: Users,
-    ) -> io::Result<ConnectionStatus> {
-        let command = Command::parse(input);
+    pub async fn execute(ctx: &Context, input: String) -> io::Result<ConnectionStatus> {
+        let Invocation { command, spec } = match commands::parse(input) {
+            Ok(invocation) => invocation,
+            Err(event) => {
+                ctx.reply_event(event).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+        if let Some(spec) = spec.filter(|spec| spec.role > Role::User) {
+            let role = Self::persisted_role(&ctx.username, &ctx.state.user_db).await;
+            if role < spec.role {
+                let response = format!("You don't have the privileges to use /{}...", spec.name);
+                ctx.reply_event(Event::error("forbidden", response)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        }
 
         match command {
             Command::PrivateMessage { target, message } => {
-                Self::send_private_message(username, target, message, users).await
+                Self::send_private_message(ctx, target, message).await
             }
-            Command::SendFile { target, file_path } => {
-                Self::send_file(username, users, target, file_path).await
+            Command::SendFile { target, spec } => Self::send_file(ctx, target, spec).await,
+            Command::AcceptFile(id) => Self::accept_file(ctx, id).await,
+            Command::ListFiles => Self::list_files(ctx).await,
+            Command::KickUser(target) => Self::kick_user(ctx, target).await,
+            Command::Ban {
+                target,
+                duration,
+                reason,
+            } => Self::sanction_user(ctx, target, SanctionKind::Ban, duration, reason).await,
+            Command::Unban(target) => Self::lift_sanction(ctx, target, SanctionKind::Ban).await,
+            Command::Mute { target, duration } => {
+                Self::sanction_user(ctx, target, SanctionKind::Mute, duration, None).await
             }
-            Command::KickUser(target) => Self::kick_user(username, target, users).await,
-            Command::JoinChannel(channel) => Self::join_channel(username, channel, users).await,
-            Command::ListUsers => Self::list_users(username, users).await,
-            Command::ListChannels => Self::list_channels(username, users).await,
-            Command::Broadcast(message) => Self::broadcast_messages(username, message, users).await,
-            Command::ProfileView => Self::profile_view(username, users).await,
-            Command::ChangeRole => Self::change_role(username, users).await,
-            Command::CloseConnection => Self::close_connection(username, users).await,
-            Command::Unknown => Self::send_unknown_command(username, users).await,
+            Command::Unmute(target) => Self::lift_sanction(ctx, target, SanctionKind::Mute).await,
+            Command::Op(target) => Self::set_operator(ctx, target, true).await,
+            Command::Deop(target) => Self::set_operator(ctx, target, false).await,
+            Command::JoinChannel(channel) => Self::join_channel(ctx, channel).await,
+            Command::CreateChannel { name, topic } => Self::create_channel(ctx, name, topic).await,
+            Command::SetTopic(topic) => Self::set_topic(ctx, topic).await,
+            Command::ListUsers => Self::list_users(ctx).await,
+            Command::ListChannels => Self::list_channels(ctx).await,
+            Command::Broadcast(message) => Self::broadcast_messages(ctx, message).await,
+            Command::ProfileView => Self::profile_view(ctx).await,
+            Command::Search {
+                query,
+                channel,
+                sender,
+            } => Self::search_messages(ctx, query, channel, sender).await,
+            Command::History {
+                target,
+                limit,
+                before,
+            } => {
+                let limit = limit.unwrap_or(ctx.state.config.limits.history_limit);
+                Self::show_history(ctx, target, limit, before).await
+            }
+            Command::GrantRole { target, role } => Self::change_role(ctx, target, role).await,
+            Command::RevokeRole(target) => Self::change_role(ctx, target, Role::User).await,
+            Command::CloseConnection => Self::close_connection(ctx).await,
+            Command::Help(topic) => Self::show_help(ctx, topic).await,
         }
     }
 
-    async fn broadcast_messages(
-        sender_name: String,
-        msg: String,
-        users: Users,
-    ) -> io::Result<ConnectionStatus> {
-        let users_guard = users.lock().await;
+    async fn broadcast_messages(ctx: &Context, msg: String) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            users,
+            message_db,
+            channel_db,
+            ..
+        } = &ctx.state;
+        let sender_name = &ctx.username;
+        // Gone from presence means the session is being kicked or replaced.
+        let Some(sender_channel) = users.channel_of(sender_name) else {
+            return Ok(ConnectionStatus::Continue);
+        };
 
-        let sender_channel = users_guard
-            .get(&sender_name)
-            .map(|user| user.get_channel())
-            .unwrap_or("general");
+        match channel_db
+            .active_sanctions(&sender_channel, sender_name)
+            .await
+        {
+            Ok(sanctions) => {
+                if let Some(sanction) = sanctions.first() {
+                    let response = format!(
+                        "You cannot talk in {} {}.",
+                        sender_channel,
+                        sanction.until()
+                    );
+                    ctx.reply_event(Event::error("muted", response)).await?;
+                    return Ok(ConnectionStatus::Continue);
+                }
+            }
+            Err(e) => error!(error = %e, "failed to check sanctions"),
+        }
 
-        println!(
-            "INFO: {} broadcasing to channel: {}",
-            sender_name, sender_channel
-        );
+        let channel = Some(sender_channel.as_str());
+        if !Self::check_flood(ctx, channel, &msg).await? {
+            return Ok(ConnectionStatus::Continue);
+        }
+
+        if let Err(e) = message_db
+            .create_message(&sender_channel, sender_name, &msg)
+            .await
+        {
+            error!(channel = %sender_channel, error = %e, "failed to store message");
+        }
+        telemetry::message_sent("channel");
 
-        for (name, user) in users_guard.iter() {
-            if name != &sender_name && user.get_channel() == sender_channel {
-                let final_msg = format!("[{}] {sender_name}: {msg}", sender_channel);
-                user.send(final_msg).await?;
+        debug!(channel = %sender_channel, "broadcasting");
+
+        for user in users.members(&sender_channel).iter() {
+            if user.username != *sender_name {
+                let event = Event::Chat {
+                    channel: sender_channel.clone(),
+                    sender: sender_name.clone(),
+                    text: msg.clone(),
+                };
+                // A recipient that is going away is not the sender's problem.
+                let _ = user.send_event(event).await;
             }
         }
         Ok(ConnectionStatus::Continue)
     }
 
-    async fn join_channel(
-        username: String,
-        channel: String,
-        users: Users,
+    async fn join_channel(ctx: &Context, channel: String) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            users, channel_db, ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        if let Err(response) = validation::check(&ChannelName { name: &channel }) {
+            ctx.reply_event(Event::error("invalid_input", response))
+                .await?;
+            return Ok(ConnectionStatus::Continue);
+        }
+        let bans = channel_db
+            .active_sanctions(&channel, username)
+            .await
+            .map(|sanctions| {
+                sanctions
+                    .into_iter()
+                    .find(|sanction| sanction.kind == SanctionKind::Ban)
+            });
+        match bans {
+            Ok(None) => {}
+            Ok(Some(ban)) => {
+                let mut response = format!("You are banned from {channel} {}", ban.until());
+                if let Some(reason) = &ban.reason {
+                    response.push_str(&format!(": {reason}"));
+                }
+                ctx.reply_event(Event::error("banned", response)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+            Err(e) => {
+                error!(%channel, error = %e, "failed to check bans");
+                let response = format!("Could not join {channel}, try again later.");
+                ctx.reply_event(Event::error("unavailable", response))
+                    .await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        }
+
+        let channel = match channel_db.find_or_create(&channel, Some(username)).await {
+            Ok(channel) => channel,
+            Err(e) => {
+                error!(%channel, error = %e, "failed to load channel");
+                let response = format!("Could not join {channel}, try again later.");
+                ctx.reply_event(Event::error("unavailable", response))
+                    .await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+
+        let moved = Self::move_user(users, username, channel.name.clone()).await?;
+        if let (Some(user), Some(topic)) = (moved, &channel.topic) {
+            user.send(format!("Topic: {topic}")).await?;
+        }
+        Self::send_channel_history(ctx, channel.name).await?;
+        Ok(ConnectionStatus::Continue)
+    }
+
+    async fn create_channel(
+        ctx: &Context,
+        name: String,
+        topic: Option<String>,
     ) -> io::Result<ConnectionStatus> {
-        let mut users_guard = users.lock().await;
-        if let Some(user) = users_guard.get_mut(&username) {
-            user.switch_channel(channel).await?;
+        if let Err(response) = validation::check(&ChannelName { name: &name }) {
+            ctx.reply_event(Event::error("invalid_input", response))
+                .await?;
+            return Ok(ConnectionStatus::Continue);
         }
+        let response = match ctx
+            .state
+            .channel_db
+            .create_channel(&name, Some(&ctx.username), topic.as_deref())
+            .await
+        {
+            Ok(Some(channel)) => format!(
+                "Channel {} created. Use /join {} to enter it.",
+                channel.name, channel.name
+            ),
+            Ok(None) => format!("Channel {name} already exists."),
+            Err(e) => {
+                error!(channel = %name, error = %e, "failed to create channel");
+                format!("Could not create {name}, try again later.")
+            }
+        };
+        ctx.reply(response).await?;
         Ok(ConnectionStatus::Continue)
     }
 
-    async fn change_role(username: String, users: Users) -> io::Result<ConnectionStatus> {
-        let mut users_guard = users.lock().await;
-        if let Some(user) = users_guard.get_mut(&username) {
-            user.change_role().await?;
-            let response = format!("You changed your role");
-            Self::send_message(username, users_guard, response).await?;
+    async fn set_topic(ctx: &Context, topic: String) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            users, channel_db, ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        let Some(channel) = users.channel_of(username) else {
+            return Ok(ConnectionStatus::Continue);
+        };
+
+        match channel_db.set_topic(&channel, &topic).await {
+            Ok(true) => {
+                let notice = format!("[{channel}] {username} set the topic to: {topic}");
+                for user in users.members(&channel).iter() {
+                    let _ = user.send(notice.clone()).await;
+                }
+            }
+            Ok(false) => {
+                let response = format!("Channel {channel} not found.");
+                ctx.reply(response).await?;
+            }
+            Err(e) => {
+                error!(%channel, error = %e, "failed to set topic");
+                let response = "Could not set the topic, try again later.".to_string();
+                ctx.reply(response).await?;
+            }
         }
         Ok(ConnectionStatus::Continue)
     }
 
-    async fn profile_view(username: String, users: Users) -> io::Result<ConnectionStatus> {
-        let mut users_guard = users.lock().await;
-        if let Some(user) = users_guard.get_mut(&username) {
-            let profile = user.get_profile();
-            Self::send_message(username, users_guard, profile).await?;
+    pub async fn send_channel_history(ctx: &Context, channel: String) -> io::Result<()> {
+        let limit = ctx.state.config.limits.history_limit;
+        let message_db = &ctx.state.message_db;
+        let messages = match message_db.recent_messages(&channel, limit).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!(%channel, error = %e, "failed to load history");
+                return Ok(());
+            }
+        };
+
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let mut history = vec![format!(
+            "--- Last {} messages in {channel} ---",
+            messages.len()
+        )];
+        for message in messages {
+            let created_at = message.created_at.format("%Y-%m-%d %H:%M");
+            history.push(format!(
+                "{created_at} [{}] {}: {}",
+                message.channel,
+                message.sender,
+                message.text.unwrap_or_default()
+            ));
+        }
+        history.push("---".to_string());
+
+        ctx.reply(history.join("\n")).await
+    }
+
+    /// Reads the caller's role from the database rather than the session so
+    /// that grants and revocations apply immediately. Fails closed to `User`.
+    async fn persisted_role(username: &str, user_db: &UserDb) -> Role {
+        match user_db.find_role(username).await {
+            Ok(role) => role.unwrap_or(Role::User),
+            Err(e) => {
+                error!(error = %e, "failed to load role");
+                Role::User
+            }
+        }
+    }
+
+    /// Admins may hand out or take away any role below their own, and only
+    /// for users who currently rank below them.
+    async fn change_role(
+        ctx: &Context,
+        target: String,
+        role: Role,
+    ) -> io::Result<ConnectionStatus> {
+        let ServerState { users, user_db, .. } = &ctx.state;
+        let actor_role = Self::persisted_role(&ctx.username, user_db).await;
+
+        let target_role = match user_db.find_role(&target).await {
+            Ok(Some(role)) => role,
+            Ok(None) => {
+                let response = format!("{target} not found...");
+                ctx.reply_event(Event::error("not_found", response)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+            Err(e) => {
+                error!(%target, error = %e, "failed to load role");
+                let response = "Could not change roles, try again later.";
+                ctx.reply_event(Event::error("unavailable", response))
+                    .await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+
+        if target_role >= actor_role || role >= actor_role {
+            let response = format!("You cannot change the role of {target} to {role}...");
+            ctx.reply_event(Event::error("forbidden", response)).await?;
+            return Ok(ConnectionStatus::Continue);
+        }
+
+        if let Err(e) = user_db.set_role(&target, role).await {
+            error!(%target, error = %e, "failed to set role");
+            let response = "Could not change roles, try again later.";
+            ctx.reply_event(Event::error("unavailable", response))
+                .await?;
+            return Ok(ConnectionStatus::Continue);
+        }
+        info!(%target, %role, "changed role");
+
+        if let Some(user) = users.update(&target, |user| user.role = role) {
+            let _ = user.send(format!("Your role is now {role}")).await;
         }
+        let response = format!("{target} is now {role}");
+        ctx.reply(response).await?;
         Ok(ConnectionStatus::Continue)
     }
 
-    async fn list_users(username: String, users: Users) -> io::Result<ConnectionStatus> {
-        let users_guard = users.lock().await;
-        let list = users_guard.keys().cloned().collect::<Vec<_>>().join(", ");
-        let response = format!("Connected users: {}\n", list);
-        Self::send_message(username, users_guard, response.to_string()).await?;
+    /// Moves `username` to `channel` and tells them. Returns their updated
+    /// session, or `None` if they are no longer online.
+    async fn move_user(users: &Users, username: &str, channel: String) -> io::Result<Option<User>> {
+        let Some((previous, user)) = users.move_to(username, channel) else {
+            return Ok(None);
+        };
+        let response = format!("Switched from {} to {}", previous, user.get_channel());
+        user.send(response).await?;
+        Ok(Some(user))
+    }
+
+    /// Ranks a user within a channel: plain members, operators, the channel
+    /// creator, then global Mods, Admins and the Owner.
+    async fn moderation_rank(
+        username: &str,
+        role: Role,
+        creator: Option<&str>,
+        channel: &str,
+        channel_db: &ChannelDb,
+    ) -> Result<u8, sqlx::Error> {
+        let global = match role {
+            Role::User => 0,
+            Role::Mod => 3,
+            Role::Admin => 4,
+            Role::Owner => 5,
+        };
+        let local = if creator == Some(username) {
+            2
+        } else if channel_db.is_operator(channel, username).await? {
+            1
+        } else {
+            0
+        };
+        Ok(global.max(local))
+    }
+
+    /// Operators, the channel creator and global Mods may moderate a channel,
+    /// but only users who rank below them there.
+    async fn authorize_moderation(ctx: &Context, target: &str, channel: &str) -> Result<(), Event> {
+        let ServerState {
+            user_db,
+            channel_db,
+            ..
+        } = &ctx.state;
+        let actor = ctx.username.as_str();
+        let unavailable = |e: sqlx::Error| {
+            error!(%channel, error = %e, "failed to check moderation rights");
+            Event::error("unavailable", "Could not moderate, try again later.")
+        };
+
+        let creator = channel_db
+            .find_by_name(channel)
+            .await
+            .map_err(unavailable)?
+            .and_then(|channel| channel.created_by);
+        let creator = creator.as_deref();
+
+        let actor_role = Self::persisted_role(actor, user_db).await;
+        let actor_rank = Self::moderation_rank(actor, actor_role, creator, channel, channel_db)
+            .await
+            .map_err(unavailable)?;
+        if actor_rank == 0 {
+            let response = format!("You don't have the privileges to moderate {channel}...");
+            return Err(Event::error("forbidden", response));
+        }
+
+        if actor == target {
+            return Err(Event::error(
+                "invalid_target",
+                "You cannot moderate yourself...",
+            ));
+        }
+
+        let Some(target_role) = user_db.find_role(target).await.map_err(unavailable)? else {
+            return Err(Event::error("not_found", format!("{target} not found...")));
+        };
+        let target_rank = Self::moderation_rank(target, target_role, creator, channel, channel_db)
+            .await
+            .map_err(unavailable)?;
+        if target_rank >= actor_rank {
+            let response = format!("You cannot moderate {target}...");
+            return Err(Event::error("forbidden", response));
+        }
+
+        Ok(())
+    }
+
+    async fn sanction_user(
+        ctx: &Context,
+        target: String,
+        kind: SanctionKind,
+        duration: Option<TimeDelta>,
+        reason: Option<String>,
+    ) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            config,
+            users,
+            channel_db,
+            ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        let channel = ctx.channel();
+        if let Err(event) = Self::authorize_moderation(ctx, &target, &channel).await {
+            ctx.reply_event(event).await?;
+            return Ok(ConnectionStatus::Continue);
+        }
+        // Everyone lands in the default channel on login, so a ban there
+        // could never be enforced.
+        if kind == SanctionKind::Ban && channel == config.default_channel {
+            let response =
+                format!("Users cannot be banned from {channel}, mute or kick them instead...");
+            ctx.reply_event(Event::error("forbidden", response)).await?;
+            return Ok(ConnectionStatus::Continue);
+        }
+
+        let expires_at = match duration {
+            Some(duration) => match Utc::now().checked_add_signed(duration) {
+                Some(expires_at) => Some(expires_at),
+                None => {
+                    let response = "That duration is too long, leave it out for a permanent one.";
+                    ctx.reply_event(Event::error("bad_request", response))
+                        .await?;
+                    return Ok(ConnectionStatus::Continue);
+                }
+            },
+            None => None,
+        };
+        let stored = channel_db
+            .add_sanction(
+                &channel,
+                &target,
+                kind,
+                reason.as_deref(),
+                Some(username),
+                expires_at,
+            )
+            .await;
+        match stored {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!(%channel, %target, ?kind, "sanction not stored, no such channel or user");
+                let response = format!("Could not moderate {target}, no such user or channel...");
+                ctx.reply_event(Event::error("not_found", response)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+            Err(e) => {
+                error!(%channel, %target, ?kind, error = %e, "failed to store sanction");
+                let response = "Could not moderate, try again later.";
+                ctx.reply_event(Event::error("unavailable", response))
+                    .await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        }
+
+        let sanction = Sanction {
+            channel: channel.clone(),
+            username: target.clone(),
+            kind,
+            reason,
+            created_by: Some(username.clone()),
+            expires_at,
+        };
+        let action = match kind {
+            SanctionKind::Ban => "banned from",
+            SanctionKind::Mute => "muted in",
+        };
+        let mut notice = format!("{action} {channel} {}", sanction.until());
+        if let Some(reason) = &sanction.reason {
+            notice.push_str(&format!(": {reason}"));
+        }
+        info!(%channel, %target, ?kind, until = %sanction.until(), "sanctioned");
+
+        if let Some(user) = users.get(&target) {
+            let _ = user.send(format!("You have been {notice}")).await;
+            if kind == SanctionKind::Ban && user.get_channel() == channel {
+                let default_channel = config.default_channel.clone();
+                let _ = Self::move_user(users, &target, default_channel).await;
+            }
+        }
+        let response = format!("{target} has been {notice}");
+        ctx.reply(response).await?;
         Ok(ConnectionStatus::Continue)
     }
 
-    async fn kick_user(
-        kicker: String,
+    /// Applies flood limits to a chat message. Returns `false` when the
+    /// message has to be dropped, after telling the sender why.
+    async fn check_flood(ctx: &Context, channel: Option<&str>, message: &str) -> io::Result<bool> {
+        let flood = &ctx.state.flood;
+        let event = match flood.check(&ctx.username, channel, message.len()).await {
+            Verdict::Allowed => return Ok(true),
+            Verdict::UserLimited { mute: false } => Event::error(
+                "rate_limited",
+                "You are sending messages too fast, slow down.",
+            ),
+            Verdict::UserLimited { mute: true } => {
+                let duration = flood.mute_duration();
+                Self::auto_mute(ctx, duration).await?;
+                return Ok(false);
+            }
+            Verdict::ChannelLimited => {
+                let channel = channel.unwrap_or_default();
+                let error = format!("{channel} is too busy right now, try again in a moment.");
+                Event::error("rate_limited", error)
+            }
+        };
+        ctx.reply_event(event).await?;
+        Ok(false)
+    }
+
+    /// Mutes a user who kept flooding in their current channel and tells
+    /// everyone online who could lift the mute.
+    async fn auto_mute(ctx: &Context, duration: Duration) -> io::Result<()> {
+        let ServerState {
+            users, channel_db, ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        let channel = ctx.channel();
+        let reason = "flooding".to_string();
+        let expires_at = Utc::now() + TimeDelta::seconds(duration.as_secs() as i64);
+        let stored = channel_db
+            .add_sanction(
+                &channel,
+                username,
+                SanctionKind::Mute,
+                Some(&reason),
+                None,
+                Some(expires_at),
+            )
+            .await;
+        match &stored {
+            Ok(true) => {}
+            Ok(false) => warn!(%channel, "flood mute not stored, no such channel"),
+            Err(e) => error!(%channel, error = %e, "failed to store flood mute"),
+        }
+        // Without a stored mute the sender is only warned.
+        if !matches!(stored, Ok(true)) {
+            let error = "You are sending messages too fast, slow down.";
+            let event = Event::error("rate_limited", error);
+            return ctx.reply_event(event).await;
+        }
+
+        let sanction = Sanction {
+            channel: channel.clone(),
+            username: username.to_string(),
+            kind: SanctionKind::Mute,
+            reason: Some(reason),
+            created_by: None,
+            expires_at: Some(expires_at),
+        };
+        warn!(%channel, %expires_at, "muted for flooding");
+
+        let operators = channel_db.operators(&channel).await.unwrap_or_else(|e| {
+            error!(%channel, error = %e, "failed to load channel operators");
+            Vec::new()
+        });
+        let until = sanction.until();
+        for user in users.all() {
+            if user.username == *username {
+                let notice = format!("You have been muted in {channel} {until} for flooding.");
+                user.send(notice).await?;
+            } else if user.role >= Role::Mod || operators.contains(&user.username) {
+                let notice = format!("{username} was muted in {channel} {until} for flooding.");
+                let _ = user.send(notice).await;
+            }
+        }
+        Ok(())
+    }
+
+    async fn lift_sanction(
+        ctx: &Context,
         target: String,
-        users: Users,
+        kind: SanctionKind,
     ) -> io::Result<ConnectionStatus> {
-        let mut users_guard = users.lock().await;
+        let channel_db = &ctx.state.channel_db;
+        let channel = ctx.channel();
+        if let Err(event) = Self::authorize_moderation(ctx, &target, &channel).await {
+            ctx.reply_event(event).await?;
+            return Ok(ConnectionStatus::Continue);
+        }
 
-        let kicker_role = users_guard
-            .get(&kicker)
-            .map(|user| user.role.as_str())
-            .unwrap_or("User");
+        let action = match kind {
+            SanctionKind::Ban => "banned from",
+            SanctionKind::Mute => "muted in",
+        };
+        let response = match channel_db.remove_sanction(&channel, &target, kind).await {
+            Ok(true) => {
+                info!(%channel, %target, ?kind, "lifted sanction");
+                let notice = format!("You are no longer {action} {channel}");
+                let _ = ctx.state.send_message(&target, notice).await;
+                format!("{target} is no longer {action} {channel}")
+            }
+            Ok(false) => format!("{target} is not {action} {channel}"),
+            Err(e) => {
+                error!(%channel, %target, ?kind, error = %e, "failed to lift sanction");
+                "Could not moderate, try again later.".to_string()
+            }
+        };
+        ctx.reply(response).await?;
+        Ok(ConnectionStatus::Continue)
+    }
 
-        if kicker_role != "Mod" {
-            let response = format!("You don't have the privileges to kick users...");
-            Self::send_message(kicker, users_guard, response).await?;
+    async fn set_operator(
+        ctx: &Context,
+        target: String,
+        operator: bool,
+    ) -> io::Result<ConnectionStatus> {
+        let channel_db = &ctx.state.channel_db;
+        let username = &ctx.username;
+        let channel = ctx.channel();
+        if let Err(event) = Self::authorize_moderation(ctx, &target, &channel).await {
+            ctx.reply_event(event).await?;
             return Ok(ConnectionStatus::Continue);
         }
 
-        if kicker == target {
-            let response = format!("You cannot kick yourself...");
-            Self::send_message(kicker, users_guard, response.to_string()).await?;
+        let result = if operator {
+            channel_db.add_operator(&channel, &target, username).await
+        } else {
+            channel_db.remove_operator(&channel, &target).await
+        };
+        let response = match result {
+            Ok(true) if operator => format!("{target} is now an operator of {channel}"),
+            Ok(true) => format!("{target} is no longer an operator of {channel}"),
+            Ok(false) => format!("{target} is not an operator of {channel}"),
+            Err(e) => {
+                error!(%channel, error = %e, "failed to change operators");
+                "Could not moderate, try again later.".to_string()
+            }
+        };
+        ctx.reply(response).await?;
+        Ok(ConnectionStatus::Continue)
+    }
+
+    async fn profile_view(ctx: &Context) -> io::Result<ConnectionStatus> {
+        if let Some(user) = ctx.state.users.get(&ctx.username) {
+            user.send(user.get_profile()).await?;
+        }
+        Ok(ConnectionStatus::Continue)
+    }
+
+    async fn list_users(ctx: &Context) -> io::Result<ConnectionStatus> {
+        let event = Event::Presence {
+            users: ctx.state.users.names(),
+        };
+        ctx.reply_event(event).await?;
+        Ok(ConnectionStatus::Continue)
+    }
+
+    async fn kick_user(ctx: &Context, target: String) -> io::Result<ConnectionStatus> {
+        let ServerState { users, user_db, .. } = &ctx.state;
+        let kicker = &ctx.username;
+        let kicker_role = Self::persisted_role(kicker, user_db).await;
+        let target_role = Self::persisted_role(&target, user_db).await;
+
+        if *kicker == target {
+            let response = "You cannot kick yourself...";
+            ctx.reply_event(Event::error("invalid_target", response))
+                .await?;
             return Ok(ConnectionStatus::Continue);
         }
 
-        if !users_guard.contains_key(&target) {
+        if !users.contains(&target) {
             let response = format!("{target} not found...");
-            Self::send_message(kicker, users_guard, response.to_string()).await?;
+            ctx.reply_event(Event::error("not_found", response)).await?;
             return Ok(ConnectionStatus::Continue);
         }
 
-        if let Some(user) = users_guard.remove(&target) {
+        if target_role >= kicker_role {
+            let response = format!("You cannot kick {target}...");
+            ctx.reply_event(Event::error("forbidden", response)).await?;
+            return Ok(ConnectionStatus::Continue);
+        }
+
+        if let Some(user) = users.remove(&target) {
             let response = format!("You have been kicked out of the server...");
-            user.send(response).await?;
+            let _ = user.send(response).await;
+            user.disconnect(CloseReason::Kicked { by: kicker.clone() });
         }
         return Ok(ConnectionStatus::Continue);
     }
 
     async fn send_private_message(
-        username: String,
+        ctx: &Context,
         target_name: String,
         msg: String,
-        users: Users,
     ) -> io::Result<ConnectionStatus> {
-        let users_guard = users.lock().await;
-        if let Some(_target_user) = users_guard.values().find(|u| u.username == target_name) {
-            let response = format!("[DM] {}: {}", username, msg);
-            Self::send_message(target_name, users_guard, response.to_string()).await?;
-        } else {
-            let error = format!("User {} not found.", target_name);
-            Self::send_message(username, users_guard, error.to_string()).await?;
+        let ServerState {
+            users, message_db, ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        if !Self::check_flood(ctx, None, &msg).await? {
+            return Ok(ConnectionStatus::Continue);
         }
+
+        let online = users.contains(&target_name);
+
+        let stored = message_db
+            .create_direct_message(username, &target_name, &msg, online)
+            .await;
+        if let Err(e) = &stored {
+            error!(recipient = %target_name, error = %e, "failed to store direct message");
+        }
+        if online || matches!(stored, Ok(Some(_))) {
+            telemetry::message_sent("direct");
+        }
+
+        // Online users get the message even if storing it failed.
+        if online {
+            let event = Event::Direct {
+                sender: username.clone(),
+                text: msg,
+                sent_at: None,
+            };
+            let _ = ctx.state.send_event(&target_name, event).await;
+            return Ok(ConnectionStatus::Continue);
+        }
+
+        let event = match stored {
+            Ok(Some(_)) => Event::Info {
+                text: format!("{target_name} is offline and will get your message on next login."),
+            },
+            Ok(None) => Event::error("not_found", format!("User {} not found.", target_name)),
+            Err(_) => {
+                let error = format!("Could not message {target_name}, try again later.");
+                Event::error("unavailable", error)
+            }
+        };
+        ctx.reply_event(event).await?;
         Ok(ConnectionStatus::Continue)
     }
 
-    async fn send_file(
-        username: String,
-        users: Users,
-        target_name: String,
-        file_path: String,
+    async fn search_messages(
+        ctx: &Context,
+        query: String,
+        channel: Option<String>,
+        sender: Option<String>,
+    ) -> io::Result<ConnectionStatus> {
+        let username = &ctx.username;
+        let results = ctx
+            .state
+            .message_db
+            .search_messages(
+                username,
+                &query,
+                channel.as_deref(),
+                sender.as_deref(),
+                SEARCH_LIMIT,
+            )
+            .await;
+
+        let event = match results {
+            Ok(results) => Event::SearchResults {
+                query,
+                hits: results
+                    .into_iter()
+                    .map(|result| SearchHit {
+                        id: result.id,
+                        channel: result.channel,
+                        sender: result.sender,
+                        recipient: result.recipient,
+                        text: result.text,
+                        sent_at: result.created_at,
+                    })
+                    .collect(),
+            },
+            Err(e) => {
+                error!(error = %e, "failed to search messages");
+                Event::error("unavailable", "Could not search messages, try again later.")
+            }
+        };
+        ctx.reply_event(event).await?;
+        Ok(ConnectionStatus::Continue)
+    }
+
+    /// Pages through a channel (`#name`) or the conversation with a user.
+    /// Channels the requester is banned from cannot be read.
+    async fn show_history(
+        ctx: &Context,
+        target: String,
+        limit: i64,
+        before: Option<i64>,
     ) -> io::Result<ConnectionStatus> {
-        let users_guard = users.lock().await;
-        if let Some(user) = users_guard.values().find(|u| u.username == target_name) {
-            let file_path = match File::open(file_path.clone()).await {
-                Ok(f) => f,
-                Err(e) => {
-                    let error = format!("ERROR: file {} not found: {}", file_path, e);
-                    eprintln!("{error}");
-                    user.send(error).await?;
+        let ServerState {
+            message_db,
+            channel_db,
+            ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        // One extra row tells whether an older page exists.
+        let entries = match target.strip_prefix('#') {
+            Some(channel) => match channel_db.active_sanctions(channel, username).await {
+                Ok(sanctions) if sanctions.iter().any(|s| s.kind == SanctionKind::Ban) => {
+                    let response = format!("You are banned from {channel}.");
+                    ctx.reply_event(Event::error("banned", response)).await?;
                     return Ok(ConnectionStatus::Continue);
                 }
+                Ok(_) => message_db
+                    .channel_history(channel, before, limit + 1)
+                    .await
+                    .map(|messages| {
+                        messages
+                            .into_iter()
+                            .map(|message| HistoryEntry {
+                                id: message.id,
+                                sender: message.sender,
+                                text: message.text.unwrap_or_default(),
+                                sent_at: message.created_at,
+                            })
+                            .collect::<Vec<_>>()
+                    }),
+                Err(e) => Err(e),
+            },
+            None => message_db
+                .conversation_history(username, &target, before, limit + 1)
+                .await
+                .map(|messages| {
+                    messages
+                        .into_iter()
+                        .map(|message| HistoryEntry {
+                            id: message.id,
+                            sender: message.sender,
+                            text: message.text,
+                            sent_at: message.created_at,
+                        })
+                        .collect::<Vec<_>>()
+                }),
+        };
+
+        let event = match entries {
+            Ok(mut entries) => {
+                let more = entries.len() as i64 > limit;
+                if more {
+                    entries.remove(0);
+                }
+                Event::History {
+                    target,
+                    entries,
+                    more,
+                }
+            }
+            Err(e) => {
+                error!(%target, error = %e, "failed to load history");
+                Event::error("unavailable", "Could not load history, try again later.")
+            }
+        };
+        ctx.reply_event(event).await?;
+        Ok(ConnectionStatus::Continue)
+    }
+
+    /// Sends direct messages that arrived while the user was offline, with
+    /// their original timestamps, preceded by an unread count. Messages are
+    /// marked delivered once queued, so a session that goes away first
+    /// leaves the rest for the next login.
+    pub async fn deliver_offline_messages(ctx: &Context) -> io::Result<()> {
+        let ServerState {
+            users, message_db, ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        let Some(user) = users.get(username) else {
+            return Ok(());
+        };
+        let messages = match message_db.undelivered_messages(username).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                error!(error = %e, "failed to load offline messages");
+                return Ok(());
+            }
+        };
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        user.send(format!(
+            "You have {} unread direct message(s).",
+            messages.len()
+        ))
+        .await?;
+        let mut queued = Vec::with_capacity(messages.len());
+        let mut result = Ok(());
+        for message in messages {
+            let event = Event::Direct {
+                sender: message.sender,
+                text: message.text,
+                sent_at: Some(message.created_at),
             };
-            println!("INFO: file transfer to {} begins", target_name);
-            let response = format!("{username} is sending {:?}", file_path);
-            user.send(response.to_string()).await?;
-            user.send("=========================================".to_string())
-                .await?;
-            //Self::send_private_message(username, target_name.clone(), response, users.clone()).await?;
-            user.send_file_stream(file_path).await?;
-            user.send("=========================================".to_string())
+            if let Err(e) = user.send_paced(event).await {
+                result = Err(e);
+                break;
+            }
+            queued.push(message.id);
+        }
+        if let Err(e) = message_db.mark_delivered(&queued).await {
+            error!(error = %e, "failed to mark offline messages delivered");
+        }
+        result
+    }
+
+    async fn send_file(
+        ctx: &Context,
+        target_name: String,
+        spec: String,
+    ) -> io::Result<ConnectionStatus> {
+        let ServerState { users, files, .. } = &ctx.state;
+        let username = &ctx.username;
+        let upload = match FileUpload::parse(&target_name, &spec, files.max_size) {
+            Ok(upload) => upload,
+            Err(error) => {
+                ctx.reply_event(Event::error("bad_request", error)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+
+        if target_name == *username {
+            let error = "You cannot send files to yourself...";
+            ctx.reply_event(Event::error("invalid_target", error))
                 .await?;
-            println!("INFO: file transfer to {} complete", target_name);
-        } else {
+            return Ok(ConnectionStatus::Continue);
+        }
+        if !users.contains(&target_name) {
             let error = format!("User {} not found.", target_name);
-            Self::send_message(username, users_guard, error.to_string()).await?;
+            ctx.reply_event(Event::error("not_found", error)).await?;
+            return Ok(ConnectionStatus::Continue);
         }
-        Ok(ConnectionStatus::Continue)
+
+        info!(file = %upload.name, recipient = %target_name, size = upload.size, "upload started");
+        let event = Event::UploadReady { size: upload.size };
+        ctx.reply_event(event).await?;
+        Ok(ConnectionStatus::ReceiveFile(upload))
     }
 
-    async fn list_channels(username: String, users: Users) -> io::Result<ConnectionStatus> {
-        let users_guard = users.lock().await;
-        let channels: HashSet<String> = users_guard
-            .values()
-            .map(|user| user.get_channel().to_string())
-            .collect();
-        let channel_list: Vec<String> = channels.into_iter().collect();
-        let response = format!("Active channels: {}", channel_list.join(", "));
-        Self::send_message(username, users_guard, response.to_string()).await?;
-        Ok(ConnectionStatus::Continue)
+    pub async fn offer_file(
+        ctx: &Context,
+        upload: FileUpload,
+        outcome: UploadOutcome,
+    ) -> io::Result<()> {
+        let stored = match outcome {
+            UploadOutcome::Stored(stored) => stored,
+            UploadOutcome::ChecksumMismatch => {
+                let error = format!("Upload of {} failed: checksum mismatch.", upload.name);
+                let event = Event::error("checksum_mismatch", error);
+                return ctx.reply_event(event).await;
+            }
+        };
+
+        let file = match ctx
+            .state
+            .message_db
+            .create_file_message(&ctx.username, &upload.target, &upload.name, &stored)
+            .await
+        {
+            Ok(file) => file,
+            Err(e) => {
+                error!(file = %upload.name, error = %e, "failed to record file");
+                let error = format!("Upload of {} failed, try again later.", upload.name);
+                return ctx.reply_event(Event::error("unavailable", error)).await;
+            }
+        };
+
+        info!(file = %file.name, id = file.id, recipient = %file.recipient, "upload complete");
+        let response = format!("Sent {} to {} (id {}).", file.name, file.recipient, file.id);
+        ctx.reply(response).await?;
+
+        let offer = Event::FileOffer {
+            id: file.id,
+            sender: file.sender,
+            name: file.name,
+            size: file.file_size,
+            sha256: file.file_sha256,
+        };
+        let _ = ctx.state.send_event(&upload.target, offer).await;
+        Ok(())
     }
 
-    async fn close_connection(username: String, users: Users) -> io::Result<ConnectionStatus> {
-        //let users_guard = users.lock().await;
-        let response = format!("GOODBYE!");
-        //Self::send_message(username, users_guard, response.to_string()).await?;
-        Self::c_send_message(username, users, response.to_string()).await?;
-        return Ok(ConnectionStatus::Close);
+    async fn accept_file(ctx: &Context, id: i64) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            users,
+            message_db,
+            files,
+            ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        let file = match message_db.find_file_for_recipient(id, username).await {
+            Ok(Some(file)) => file,
+            Ok(None) => {
+                let error = format!("File {id} not found.");
+                ctx.reply_event(Event::error("not_found", error)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+            Err(e) => {
+                error!(id, error = %e, "failed to look up file");
+                let error = "Could not load the file, try again later.";
+                ctx.reply_event(Event::error("unavailable", error)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+
+        let handle = match files.open(&file.file_url).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                error!(id, error = %e, "failed to open file");
+                let error = format!("File {id} is no longer available.");
+                ctx.reply_event(Event::error("not_found", error)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+
+        let Some(user) = users.get(username) else {
+            return Ok(ConnectionStatus::Continue);
+        };
+
+        info!(file = %file.name, id = file.id, "download started");
+        user.send_event(Event::FileStart {
+            id: file.id,
+            name: file.name,
+            size: file.file_size,
+            sha256: file.file_sha256,
+        })
+        .await?;
+        user.send_file_stream(file.id, handle).await?;
+        user.send_event(Event::FileEnd { id: file.id }).await?;
+        info!(id, "download complete");
+        Ok(ConnectionStatus::Continue)
     }
 
-    pub async fn c_send_message(
-        target: String,
-        users: Arc<Mutex<HashMap<String, User>>>,
-        message: String,
-    ) -> io::Result<()> {
-        let sender = {
-            let users = users.lock().await;
-            users.get(&target).cloned()
+    async fn list_files(ctx: &Context) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            users, message_db, ..
+        } = &ctx.state;
+        let username = &ctx.username;
+        let files = match message_db.files_for_recipient(username, FILES_LIMIT).await {
+            Ok(files) => files,
+            Err(e) => {
+                error!(error = %e, "failed to list files");
+                let error = "Could not list files, try again later.";
+                ctx.reply_event(Event::error("unavailable", error)).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
         };
 
-        if let Some(sender) = sender {
-            sender.send(message).await?;
+        if files.is_empty() {
+            let response = "No files have been sent to you.".to_string();
+            ctx.reply(response).await?;
+            return Ok(ConnectionStatus::Continue);
         }
 
-        Ok(())
+        if let Some(user) = users.get(username) {
+            for file in files {
+                user.send_event(Event::FileOffer {
+                    id: file.id,
+                    sender: file.sender,
+                    name: file.name,
+                    size: file.file_size,
+                    sha256: file.file_sha256,
+                })
+                .await?;
+            }
+        }
+        Ok(ConnectionStatus::Continue)
     }
 
-    pub async fn send_message(
-        target: String,
-        mut users_guard: tokio::sync::MutexGuard<'_, HashMap<String, User>>,
-        message: String,
-    ) -> io::Result<()> {
-        if let Some(user) = users_guard.get_mut(&target) {
-            user.send(message).await?;
+    async fn list_channels(ctx: &Context) -> io::Result<ConnectionStatus> {
+        let ServerState {
+            users, channel_db, ..
+        } = &ctx.state;
+        let channels = match channel_db.get_all_channels().await {
+            Ok(channels) => channels,
+            Err(e) => {
+                error!(error = %e, "failed to list channels");
+                let response = "Could not list channels, try again later.".to_string();
+                ctx.reply(response).await?;
+                return Ok(ConnectionStatus::Continue);
+            }
+        };
+
+        let members = users.channel_sizes();
+        let mut lines = vec!["Channels:".to_string()];
+        for channel in &channels {
+            let count = members.get(channel.name.as_str()).copied().unwrap_or(0);
+            let created_at = channel.created_at.format("%Y-%m-%d");
+            let created_by = channel.created_by.as_deref().unwrap_or("server");
+            let mut line = format!(
+                "  {} ({} online) created by {} on {}",
+                channel.name, count, created_by, created_at
+            );
+            if let Some(topic) = &channel.topic {
+                line.push_str(&format!(" - {topic}"));
+            }
+            lines.push(line);
         }
-        Ok(())
+        let response = lines.join("\n");
+        ctx.reply(response).await?;
+        Ok(ConnectionStatus::Continue)
+    }
+
+    async fn close_connection(ctx: &Context) -> io::Result<ConnectionStatus> {
+        let response = format!("GOODBYE!");
+        ctx.reply(response.to_string()).await?;
+        return Ok(ConnectionStatus::Close);
     }
 
-    async fn send_unknown_command(username: String, users: Users) -> io::Result<ConnectionStatus> {
-        let mut users_guard = users.lock().await;
-        if let Some(user) = users_guard.get_mut(&username) {
-            let help = r#"
-            Available commands:
-            /msg <user> <message> - Send private message
-            /join <channel> - Switch channels
-            /list - List online users
-            /channels - List active channels
-            /profile - Show your profile
-            /close - Close the connection
-            /help = To show this message
-            "#;
-            user.send(help.trim().to_string()).await?;
+    async fn show_help(ctx: &Context, topic: Option<String>) -> io::Result<ConnectionStatus> {
+        let Some(topic) = topic else {
+            ctx.reply(commands::overview()).await?;
+            return Ok(ConnectionStatus::Continue);
+        };
+        match commands::find(&topic) {
+            Some(spec) => ctx.reply(spec.describe()).await?,
+            None => {
+                let name = topic.trim_start_matches('/');
+                let response =
+                    format!("Unknown command /{name}. Type /help for a list of commands.");
+                ctx.reply_event(Event::error("unknown_command", response))
+                    .await?;
+            }
         }
         Ok(ConnectionStatus::Continue)
     }
@@ -1,11 +1,133 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
+use std::fmt;
+use std::str::FromStr;
+
+/// Server-wide roles, ordered from least to most privileged.
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, sqlx::Type, Serialize, Deserialize,
+)]
+#[sqlx(type_name = "user_role", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Mod,
+    Admin,
+    Owner,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::User => "User",
+            Role::Mod => "Mod",
+            Role::Admin => "Admin",
+            Role::Owner => "Owner",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "user" => Ok(Role::User),
+            "mod" => Ok(Role::Mod),
+            "admin" => Ok(Role::Admin),
+            "owner" => Ok(Role::Owner),
+            _ => Err(format!("Unknown role: {s}")),
+        }
+    }
+}
 
 #[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
 pub struct User {
     pub id: i64,
     pub username: String,
     pub password_hash: String,
+    pub role: Role,
+    pub created_at: DateTime<Utc>,
+    /// Set while the account is locked after repeated failed logins.
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Message {
+    pub id: i64,
+    pub channel: String,
+    pub sender: String,
+    pub text: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct DirectMessage {
+    pub id: i64,
+    pub sender: String,
+    pub recipient: String,
+    pub text: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct SearchResult {
+    pub id: i64,
+    pub channel: Option<String>,
+    pub sender: String,
+    pub recipient: Option<String>,
+    pub text: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Channel {
+    pub id: i64,
+    pub name: String,
+    pub topic: Option<String>,
+    pub created_by: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct FileMessage {
+    pub id: i64,
+    pub sender: String,
+    pub recipient: String,
+    pub name: String,
+    pub file_url: String,
+    pub file_type: String,
+    pub file_size: i64,
+    pub file_sha256: String,
     pub created_at: DateTime<Utc>,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, sqlx::Type, Serialize, Deserialize)]
+#[sqlx(type_name = "sanction_kind", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SanctionKind {
+    Ban,
+    Mute,
+}
+
+#[derive(Debug, Clone, FromRow, Serialize, Deserialize)]
+pub struct Sanction {
+    pub channel: String,
+    pub username: String,
+    pub kind: SanctionKind,
+    pub reason: Option<String>,
+    pub created_by: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl Sanction {
+    /// Describes how long the sanction lasts, e.g. for notices to the target.
+    pub fn until(&self) -> String {
+        match self.expires_at {
+            Some(expires_at) => format!("until {}", expires_at.format("%Y-%m-%d %H:%M UTC")),
+            None => "permanently".to_string(),
+        }
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+use crate::telemetry;
+use crate::users::UserMessage;
+
+/// File chunks queued at most per connection. A download waits for the
+/// client to catch up instead of reading the whole file into memory.
+const FILE_WINDOW: usize = 8;
+
+/// How a message is treated when the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageClass {
+    /// Chat and direct messages. The oldest is dropped to make room.
+    Chat,
+    /// Replies, notices and errors. If no chat message can be dropped for
+    /// them, the client is too slow and gets disconnected.
+    Control,
+    /// File chunks and replayed messages. The sender waits for room, up to
+    /// `FILE_WINDOW`.
+    File,
+}
+
+#[derive(Debug)]
+pub enum PushError {
+    /// The writer is gone.
+    Closed,
+    /// No room was left. Everything queued has been discarded.
+    Overflow,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<(MessageClass, UserMessage)>,
+    /// Chat and control messages in `queue`.
+    messages: usize,
+    /// File chunks in `queue`.
+    chunks: usize,
+    /// Live `Outbox` handles. The writer stops once all are gone.
+    senders: usize,
+    closed: bool,
+}
+
+#[derive(Debug)]
+struct Shared {
+    capacity: usize,
+    state: Mutex<State>,
+    /// Wakes the writer when something was queued.
+    readable: Notify,
+    /// Wakes file senders when a chunk was written or the queue closed.
+    writable: Notify,
+    /// Wakes `closed` waiters once the writer is gone.
+    gone: Notify,
+}
+
+/// Sending half of a connection's outbound queue. Unlike an mpsc channel
+/// it can drop queued messages, which the chat policy needs.
+#[derive(Debug)]
+pub struct Outbox {
+    shared: Arc<Shared>,
+}
+
+/// Receiving half, owned by the connection's writer task. Dropping it
+/// closes the queue.
+#[derive(Debug)]
+pub struct OutboxReceiver {
+    shared: Arc<Shared>,
+}
+
+/// Creates a queue holding up to `capacity` chat and control messages.
+pub fn outbox(capacity: usize) -> (Outbox, OutboxReceiver) {
+    let shared = Arc::new(Shared {
+        capacity,
+        state: Mutex::new(State {
+            senders: 1,
+            ..State::default()
+        }),
+        readable: Notify::new(),
+        writable: Notify::new(),
+        gone: Notify::new(),
+    });
+    (
+        Outbox {
+            shared: shared.clone(),
+        },
+        OutboxReceiver { shared },
+    )
+}
+
+impl Outbox {
+    /// Queues a chat or control message without waiting.
+    pub fn push(&self, class: MessageClass, message: UserMessage) -> Result<(), PushError> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.closed {
+            return Err(PushError::Closed);
+        }
+        if state.messages >= self.shared.capacity {
+            let oldest_chat = state
+                .queue
+                .iter()
+                .position(|(class, _)| *class == MessageClass::Chat);
+            match oldest_chat {
+                Some(index) => {
+                    state.queue.remove(index);
+                    state.messages -= 1;
+                    telemetry::outbound_dropped();
+                }
+                None => {
+                    state.queue.clear();
+                    state.messages = 0;
+                    state.chunks = 0;
+                    self.shared.writable.notify_waiters();
+                    return Err(PushError::Overflow);
+                }
+            }
+        }
+        state.queue.push_back((class, message));
+        state.messages += 1;
+        self.shared.readable.notify_one();
+        Ok(())
+    }
+
+    /// Queues a file chunk or a paced message, waiting while `FILE_WINDOW`
+    /// of them are queued. Never dropped.
+    pub async fn push_chunk(&self, message: UserMessage) -> Result<(), PushError> {
+        loop {
+            let writable = self.shared.writable.notified();
+            tokio::pin!(writable);
+            writable.as_mut().enable();
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if state.closed {
+                    return Err(PushError::Closed);
+                }
+                if state.chunks < FILE_WINDOW {
+                    state.queue.push_back((MessageClass::File, message));
+                    state.chunks += 1;
+                    self.shared.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            writable.await;
+        }
+    }
+
+    /// Queues `Close` behind everything else, regardless of capacity.
+    pub fn close(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        if !state.closed {
+            state
+                .queue
+                .push_back((MessageClass::Control, UserMessage::Close));
+            state.messages += 1;
+            self.shared.readable.notify_one();
+        }
+    }
+
+    /// Messages and chunks currently queued.
+    pub fn depth(&self) -> usize {
+        self.shared.state.lock().unwrap().queue.len()
+    }
+
+    pub fn same_outbox(&self, other: &Outbox) -> bool {
+        Arc::ptr_eq(&self.shared, &other.shared)
+    }
+
+    /// Resolves once the receiving half has been dropped.
+    pub async fn closed(&self) {
+        loop {
+            let gone = self.shared.gone.notified();
+            tokio::pin!(gone);
+            gone.as_mut().enable();
+            if self.shared.state.lock().unwrap().closed {
+                return;
+            }
+            gone.await;
+        }
+    }
+}
+
+impl Clone for Outbox {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.readable.notify_one();
+        }
+    }
+}
+
+impl OutboxReceiver {
+    /// Takes the next message, or returns `None` once the queue is closed.
+    /// Cancel safe: nothing is taken unless it is returned.
+    pub async fn recv(&mut self) -> Option<UserMessage> {
+        loop {
+            {
+                let mut state = self.shared.state.lock().unwrap();
+                if let Some((class, message)) = state.queue.pop_front() {
+                    match class {
+                        MessageClass::File => {
+                            state.chunks -= 1;
+                            self.shared.writable.notify_waiters();
+                        }
+                        MessageClass::Chat | MessageClass::Control => state.messages -= 1,
+                    }
+                    return Some(message);
+                }
+                if state.closed || state.senders == 0 {
+                    return None;
+                }
+            }
+            self.shared.readable.notified().await;
+        }
+    }
+}
+
+impl Drop for OutboxReceiver {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.closed = true;
+        state.queue.clear();
+        self.shared.writable.notify_waiters();
+        self.shared.gone.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn text(s: &str) -> UserMessage {
+        UserMessage::Text(s.to_string())
+    }
+
+    async fn next_text(rx: &mut OutboxReceiver) -> String {
+        match rx.recv().await {
+            Some(UserMessage::Text(s)) => s,
+            other => panic!("expected a text message, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_queue_drops_the_oldest_chat() {
+        let (tx, mut rx) = outbox(2);
+        tx.push(MessageClass::Chat, text("1")).unwrap();
+        tx.push(MessageClass::Chat, text("2")).unwrap();
+        tx.push(MessageClass::Chat, text("3")).unwrap();
+
+        assert_eq!(next_text(&mut rx).await, "2");
+        assert_eq!(next_text(&mut rx).await, "3");
+        assert_eq!(tx.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn control_messages_evict_chat_first() {
+        let (tx, mut rx) = outbox(2);
+        tx.push(MessageClass::Control, text("reply")).unwrap();
+        tx.push(MessageClass::Chat, text("chat")).unwrap();
+        tx.push(MessageClass::Control, text("notice")).unwrap();
+
+        assert_eq!(next_text(&mut rx).await, "reply");
+        assert_eq!(next_text(&mut rx).await, "notice");
+    }
+
+    #[tokio::test]
+    async fn overflow_without_chat_clears_the_queue() {
+        let (tx, _rx) = outbox(1);
+        tx.push(MessageClass::Control, text("reply")).unwrap();
+
+        let result = tx.push(MessageClass::Control, text("notice"));
+        assert!(matches!(result, Err(PushError::Overflow)));
+        assert_eq!(tx.depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn chunks_wait_for_the_window_instead_of_overflowing() {
+        let (tx, mut rx) = outbox(1);
+        tx.push(MessageClass::Control, text("reply")).unwrap();
+        for _ in 0..FILE_WINDOW {
+            tx.push_chunk(text("chunk")).await.unwrap();
+        }
+
+        let blocked = tokio::time::timeout(Duration::from_millis(50), tx.push_chunk(text("late")));
+        assert!(blocked.await.is_err());
+
+        assert_eq!(next_text(&mut rx).await, "reply");
+        assert_eq!(next_text(&mut rx).await, "chunk");
+        tx.push_chunk(text("late")).await.unwrap();
+        assert_eq!(tx.depth(), FILE_WINDOW);
+    }
+
+    #[tokio::test]
+    async fn pushes_fail_once_the_writer_is_gone() {
+        let (tx, rx) = outbox(4);
+        drop(rx);
+
+        assert!(matches!(
+            tx.push(MessageClass::Chat, text("hi")),
+            Err(PushError::Closed)
+        ));
+        assert!(matches!(
+            tx.push_chunk(text("chunk")).await,
+            Err(PushError::Closed)
+        ));
+        tx.closed().await;
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::sync::{Arc, RwLock};
+
+/// Session maps are split by username hash so lookups for different users
+/// rarely contend.
+const SHARDS: usize = 16;
+
+/// What the registry needs to know about a session.
+pub trait Member: Clone {
+    fn name(&self) -> &str;
+    fn channel(&self) -> &str;
+    fn set_channel(&mut self, channel: String);
+    fn is_same_session(&self, other: &Self) -> bool;
+}
+
+/// Who is online and in which channel, shared by all connections.
+///
+/// Locks are only held to read or update the maps and never across an
+/// `.await`: readers get clones and do their I/O afterwards. Each channel
+/// keeps its own member list, so broadcasting costs as much as the
+/// channel is large, not the whole server.
+pub struct Presence<M> {
+    inner: Arc<Inner<M>>,
+}
+
+struct Inner<M> {
+    hasher: RandomState,
+    shards: Vec<RwLock<HashMap<String, M>>>,
+    /// Copy-on-write member lists. A broadcast takes the `Arc` and lets go
+    /// of the lock; joins and leaves replace the list.
+    channels: RwLock<HashMap<String, Arc<Vec<M>>>>,
+}
+
+impl<M> Clone for Presence<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<M: Member> Default for Presence<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<M: Member> Presence<M> {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                hasher: RandomState::new(),
+                shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
+                channels: RwLock::default(),
+            }),
+        }
+    }
+
+    fn shard(&self, name: &str) -> &RwLock<HashMap<String, M>> {
+        let index = self.inner.hasher.hash_one(name) as usize % SHARDS;
+        &self.inner.shards[index]
+    }
+
+    /// Adds a session and returns the one it replaced, if any.
+    pub fn insert(&self, member: M) -> Option<M> {
+        let mut shard = self.shard(member.name()).write().unwrap();
+        let previous = shard.insert(member.name().to_string(), member.clone());
+        let mut channels = self.inner.channels.write().unwrap();
+        if let Some(previous) = &previous {
+            Self::leave(&mut channels, previous);
+        }
+        Self::join(&mut channels, &member);
+        previous
+    }
+
+    /// Removes `member` unless a newer session has taken its place.
+    pub fn remove_session(&self, member: &M) -> bool {
+        let mut shard = self.shard(member.name()).write().unwrap();
+        if !shard
+            .get(member.name())
+            .is_some_and(|current| current.is_same_session(member))
+        {
+            return false;
+        }
+        let removed = shard.remove(member.name());
+        if let Some(removed) = &removed {
+            Self::leave(&mut self.inner.channels.write().unwrap(), removed);
+        }
+        true
+    }
+
+    pub fn remove(&self, name: &str) -> Option<M> {
+        let mut shard = self.shard(name).write().unwrap();
+        let removed = shard.remove(name);
+        if let Some(removed) = &removed {
+            Self::leave(&mut self.inner.channels.write().unwrap(), removed);
+        }
+        removed
+    }
+
+    pub fn get(&self, name: &str) -> Option<M> {
+        self.shard(name).read().unwrap().get(name).cloned()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.shard(name).read().unwrap().contains_key(name)
+    }
+
+    pub fn channel_of(&self, name: &str) -> Option<String> {
+        let shard = self.shard(name).read().unwrap();
+        shard.get(name).map(|member| member.channel().to_string())
+    }
+
+    /// Changes a session in place and returns the updated copy. `update`
+    /// must not change the channel; use `move_to` for that.
+    pub fn update(&self, name: &str, update: impl Fn(&mut M)) -> Option<M> {
+        let mut shard = self.shard(name).write().unwrap();
+        let member = shard.get_mut(name)?;
+        update(member);
+        let member = member.clone();
+        let mut channels = self.inner.channels.write().unwrap();
+        Self::leave(&mut channels, &member);
+        Self::join(&mut channels, &member);
+        Some(member)
+    }
+
+    /// Moves a session to `channel`. Returns the channel it left and the
+    /// updated copy.
+    pub fn move_to(&self, name: &str, channel: String) -> Option<(String, M)> {
+        let mut shard = self.shard(name).write().unwrap();
+        let member = shard.get_mut(name)?;
+        let mut channels = self.inner.channels.write().unwrap();
+        Self::leave(&mut channels, member);
+        let previous = member.channel().to_string();
+        member.set_channel(channel);
+        Self::join(&mut channels, member);
+        Some((previous, member.clone()))
+    }
+
+    /// Everyone currently in `channel`.
+    pub fn members(&self, channel: &str) -> Arc<Vec<M>> {
+        let channels = self.inner.channels.read().unwrap();
+        channels.get(channel).cloned().unwrap_or_default()
+    }
+
+    /// Online usernames in alphabetical order.
+    pub fn names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for shard in &self.inner.shards {
+            names.extend(shard.read().unwrap().keys().cloned());
+        }
+        names.sort();
+        names
+    }
+
+    pub fn all(&self) -> Vec<M> {
+        let mut members = Vec::new();
+        for shard in &self.inner.shards {
+            members.extend(shard.read().unwrap().values().cloned());
+        }
+        members
+    }
+
+    /// Number of users in each non-empty channel.
+    pub fn channel_sizes(&self) -> HashMap<String, usize> {
+        let channels = self.inner.channels.read().unwrap();
+        channels
+            .iter()
+            .map(|(channel, members)| (channel.clone(), members.len()))
+            .collect()
+    }
+
+    fn join(channels: &mut HashMap<String, Arc<Vec<M>>>, member: &M) {
+        let members = channels.entry(member.channel().to_string()).or_default();
+        let mut updated = Vec::with_capacity(members.len() + 1);
+        updated.extend(members.iter().cloned());
+        updated.push(member.clone());
+        *members = Arc::new(updated);
+    }
+
+    fn leave(channels: &mut HashMap<String, Arc<Vec<M>>>, member: &M) {
+        let Some(members) = channels.get_mut(member.channel()) else {
+            return;
+        };
+        let updated: Vec<M> = members
+            .iter()
+            .filter(|other| other.name() != member.name())
+            .cloned()
+            .collect();
+        if updated.is_empty() {
+            channels.remove(member.channel());
+        } else {
+            *members = Arc::new(updated);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Session {
+        name: String,
+        channel: String,
+        id: u32,
+    }
+
+    impl Member for Session {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn channel(&self) -> &str {
+            &self.channel
+        }
+
+        fn set_channel(&mut self, channel: String) {
+            self.channel = channel;
+        }
+
+        fn is_same_session(&self, other: &Self) -> bool {
+            self.id == other.id
+        }
+    }
+
+    fn session(name: &str, channel: &str, id: u32) -> Session {
+        Session {
+            name: name.to_string(),
+            channel: channel.to_string(),
+            id,
+        }
+    }
+
+    fn names_in(presence: &Presence<Session>, channel: &str) -> Vec<String> {
+        let mut names: Vec<String> = presence
+            .members(channel)
+            .iter()
+            .map(|member| member.name.clone())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn insert_returns_the_replaced_session() {
+        let presence = Presence::new();
+        assert_eq!(presence.insert(session("alice", "Global", 1)), None);
+
+        let replaced = presence.insert(session("alice", "Rust", 2));
+        assert_eq!(replaced, Some(session("alice", "Global", 1)));
+        assert_eq!(presence.get("alice"), Some(session("alice", "Rust", 2)));
+        assert!(presence.members("Global").is_empty());
+        assert_eq!(*presence.members("Rust"), vec![session("alice", "Rust", 2)]);
+        assert_eq!(
+            presence.channel_sizes(),
+            HashMap::from([("Rust".to_string(), 1)])
+        );
+    }
+
+    #[test]
+    fn stale_session_cannot_remove_its_replacement() {
+        let presence = Presence::new();
+        let old = session("alice", "Global", 1);
+        let new = session("alice", "Global", 2);
+        presence.insert(old.clone());
+        presence.insert(new.clone());
+
+        assert!(!presence.remove_session(&old));
+        assert_eq!(presence.get("alice"), Some(new.clone()));
+        assert_eq!(*presence.members("Global"), vec![new.clone()]);
+
+        assert!(presence.remove_session(&new));
+        assert!(!presence.contains("alice"));
+        assert!(presence.members("Global").is_empty());
+        assert!(presence.channel_sizes().is_empty());
+    }
+
+    #[test]
+    fn move_to_keeps_channel_lists_consistent() {
+        let presence = Presence::new();
+        presence.insert(session("alice", "Global", 1));
+        presence.insert(session("bob", "Global", 2));
+        let snapshot = presence.members("Global");
+
+        let moved = presence.move_to("alice", "Rust".to_string());
+        assert_eq!(
+            moved,
+            Some(("Global".to_string(), session("alice", "Rust", 1)))
+        );
+        assert_eq!(snapshot.len(), 2, "taken lists are never changed in place");
+        assert_eq!(names_in(&presence, "Global"), ["bob"]);
+        assert_eq!(names_in(&presence, "Rust"), ["alice"]);
+        assert_eq!(presence.channel_of("alice").as_deref(), Some("Rust"));
+
+        presence.move_to("bob", "Rust".to_string());
+        assert_eq!(names_in(&presence, "Rust"), ["alice", "bob"]);
+        assert_eq!(
+            presence.channel_sizes(),
+            HashMap::from([("Rust".to_string(), 2)])
+        );
+        assert_eq!(presence.move_to("carol", "Rust".to_string()), None);
+    }
+}
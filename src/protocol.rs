@@ -0,0 +1,322 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const HELLO: &str = "WUR2/1 FRAMED";
+pub const HELLO_OK: &str = "WUR2/1 OK";
+/// Text forms of the `ping` and `pong` frames.
+pub const PING: &str = "PING";
+pub const PONG: &str = "PONG";
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+/// Longest command or chat line accepted from a client, in bytes.
+pub const MAX_LINE_LEN: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Text,
+    Framed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Frame {
+    pub v: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub id: i64,
+    pub sender: String,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub id: i64,
+    /// `None` for direct messages, which carry a `recipient` instead.
+    pub channel: Option<String>,
+    pub sender: String,
+    pub recipient: Option<String>,
+    pub text: String,
+    pub sent_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", content = "payload", rename_all = "snake_case")]
+pub enum Event {
+    Command {
+        text: String,
+    },
+    Info {
+        text: String,
+    },
+    Prompt {
+        text: String,
+    },
+    Chat {
+        channel: String,
+        sender: String,
+        text: String,
+    },
+    Direct {
+        sender: String,
+        text: String,
+        /// Set when the message is delivered later than it was sent.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        sent_at: Option<DateTime<Utc>>,
+    },
+    Presence {
+        users: Vec<String>,
+    },
+    /// A page of stored messages for `target`, a user name or `#channel`.
+    /// `more` is set when older messages exist before the first entry.
+    History {
+        target: String,
+        entries: Vec<HistoryEntry>,
+        more: bool,
+    },
+    SearchResults {
+        query: String,
+        hits: Vec<SearchHit>,
+    },
+    Error {
+        code: String,
+        message: String,
+    },
+    AuthResult {
+        ok: bool,
+        code: String,
+        username: Option<String>,
+        message: String,
+    },
+    FileOffer {
+        id: i64,
+        sender: String,
+        name: String,
+        size: i64,
+        sha256: String,
+    },
+    UploadReady {
+        size: u64,
+    },
+    UploadChunk {
+        data: String,
+    },
+    FileStart {
+        id: i64,
+        name: String,
+        size: i64,
+        sha256: String,
+    },
+    FileChunk {
+        id: i64,
+        data: String,
+    },
+    FileEnd {
+        id: i64,
+    },
+    Ack,
+    /// Heartbeat, answered with `Pong`. Sent by the server to framed
+    /// clients and accepted from clients in either mode.
+    Ping,
+    Pong,
+}
+
+impl Frame {
+    pub fn new(id: Option<u64>, event: Event) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            id,
+            event,
+        }
+    }
+
+    pub fn encode(&self) -> io::Result<Vec<u8>> {
+        let body = serde_json::to_vec(self).map_err(io::Error::other)?;
+        if body.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "frame exceeds maximum length",
+            ));
+        }
+        let mut buf = Vec::with_capacity(4 + body.len());
+        buf.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+
+    pub fn decode(body: &[u8]) -> io::Result<Self> {
+        let frame: Frame = serde_json::from_slice(body)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if frame.v != PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported protocol version {}", frame.v),
+            ));
+        }
+        Ok(frame)
+    }
+}
+
+impl Event {
+    pub fn error(code: &str, message: impl Into<String>) -> Self {
+        Event::Error {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    /// Renders the event the way the line-based protocol shows it to
+    /// `telnet`/`nc` users. Returns `None` for events with no text form.
+    pub fn to_text(&self) -> Option<String> {
+        match self {
+            Event::Command { text } | Event::Info { text } | Event::Prompt { text } => {
+                Some(text.clone())
+            }
+            Event::Chat {
+                channel,
+                sender,
+                text,
+            } => Some(format!("[{channel}] {sender}: {text}")),
+            Event::Direct {
+                sender,
+                text,
+                sent_at: None,
+            } => Some(format!("[DM] {sender}: {text}")),
+            Event::Direct {
+                sender,
+                text,
+                sent_at: Some(sent_at),
+            } => Some(format!(
+                "[DM {}] {sender}: {text}",
+                sent_at.format("%Y-%m-%d %H:%M")
+            )),
+            Event::Presence { users } => Some(format!("Connected users: {}", users.join(", "))),
+            Event::History {
+                target,
+                entries,
+                more,
+            } => {
+                let mut lines = vec![format!("--- History of {target} ---")];
+                for entry in entries {
+                    lines.push(format!(
+                        "#{} {} {}: {}",
+                        entry.id,
+                        entry.sent_at.format("%Y-%m-%d %H:%M"),
+                        entry.sender,
+                        entry.text
+                    ));
+                }
+                match entries.first() {
+                    Some(first) if *more => lines.push(format!(
+                        "--- /history {target} before {} for older messages ---",
+                        first.id
+                    )),
+                    None => lines.push("--- No messages ---".to_string()),
+                    _ => lines.push("---".to_string()),
+                }
+                Some(lines.join("\n"))
+            }
+            Event::SearchResults { query, hits } => {
+                let mut lines = vec![format!("--- {} result(s) for: {query} ---", hits.len())];
+                for hit in hits {
+                    let place = match (&hit.channel, &hit.recipient) {
+                        (Some(channel), _) => channel.clone(),
+                        (None, Some(recipient)) => format!("DM to {recipient}"),
+                        (None, None) => "DM".to_string(),
+                    };
+                    lines.push(format!(
+                        "#{} {} [{place}] {}: {}",
+                        hit.id,
+                        hit.sent_at.format("%Y-%m-%d %H:%M"),
+                        hit.sender,
+                        hit.text
+                    ));
+                }
+                lines.push("---".to_string());
+                Some(lines.join("\n"))
+            }
+            Event::Error { message, .. } => Some(message.clone()),
+            Event::AuthResult {
+                ok: true, username, ..
+            } => Some(format!(
+                "AUTH OK {}",
+                username.as_deref().unwrap_or_default()
+            )),
+            Event::AuthResult { code, message, .. } => Some(format!("AUTH ERR {code} {message}")),
+            Event::FileOffer {
+                id,
+                sender,
+                name,
+                size,
+                ..
+            } => Some(format!(
+                "{sender} wants to send you {name} ({size} bytes). Type /accept {id} to download."
+            )),
+            Event::UploadReady { size } => Some(format!("READY {size}")),
+            Event::FileStart { id, name, size, .. } => Some(format!("FILE {id} {name} {size}")),
+            Event::FileEnd { id } => Some(format!("END {id}")),
+            Event::UploadChunk { .. } | Event::FileChunk { .. } => None,
+            Event::Ack => None,
+            Event::Ping => Some(PING.to_string()),
+            Event::Pong => Some(PONG.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_round_trips_through_its_length_prefix() {
+        let frame = Frame::new(
+            Some(7),
+            Event::Chat {
+                channel: "Global".to_string(),
+                sender: "alice".to_string(),
+                text: "hello".to_string(),
+            },
+        );
+        let bytes = frame.encode().unwrap();
+        let len = u32::from_be_bytes(bytes[..4].try_into().unwrap()) as usize;
+        assert_eq!(len, bytes.len() - 4);
+
+        let decoded = Frame::decode(&bytes[4..]).unwrap();
+        assert_eq!(decoded.v, PROTOCOL_VERSION);
+        assert_eq!(decoded.id, Some(7));
+        assert!(matches!(
+            decoded.event,
+            Event::Chat { channel, sender, text }
+                if channel == "Global" && sender == "alice" && text == "hello"
+        ));
+    }
+
+    #[test]
+    fn encode_rejects_frames_over_the_cap() {
+        let text = "x".repeat(MAX_FRAME_LEN);
+        let err = Frame::new(None, Event::Info { text }).encode().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_rejects_other_versions_and_bad_json() {
+        let err = Frame::decode(br#"{"v":2,"type":"ack"}"#).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = Frame::decode(b"{not json").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn decode_accepts_frames_without_id() {
+        let frame = Frame::decode(br#"{"v":1,"type":"command","payload":{"text":"/list"}}"#);
+        let frame = frame.unwrap();
+        assert_eq!(frame.id, None);
+        assert!(matches!(frame.event, Event::Command { text } if text == "/list"));
+    }
+}
@@ -1,91 +1,379 @@
+use socket2::{SockRef, TcpKeepalive};
 use sqlx::PgPool;
-use std::collections::HashMap;
 use std::sync::Arc;
-use tokio::io::{self, AsyncBufReadExt, BufReader};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::Mutex;
+use tokio::sync::{mpsc, watch};
+use tokio::time::{self, Instant};
+use tokio_rustls::TlsAcceptor;
+use tracing::{Instrument, Span, debug, error, field, info, info_span, warn};
 
 use crate::auth::Auth;
-use crate::db::UserDb;
-use crate::messages::CommandExecutor;
-use crate::users::User;
+use crate::config::Config;
+use crate::connection::{FrameReader, FrameWriter};
+use crate::files::{FileStore, FileUpload};
+use crate::messages::{CommandExecutor, Context};
+use crate::models::Role;
+use crate::protocol::{Event, PING, PONG};
+use crate::state::ServerState;
+use crate::telemetry;
+use crate::users::{CloseReason, User};
 
 pub enum ConnectionStatus {
     Continue,
+    ReceiveFile(FileUpload),
     Close,
 }
 
-pub type Users = Arc<Mutex<HashMap<String, User>>>;
+/// Idle time before the kernel starts probing a connection. Catches peers
+/// that vanished without closing the socket, e.g. while uploading.
+const KEEPALIVE_TIME: Duration = Duration::from_secs(60);
+
+/// Held by every listener and connection task. Shutdown is complete once
+/// all clones have been dropped.
+#[derive(Clone)]
+struct Shutdown {
+    signal: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+}
+
+impl Shutdown {
+    async fn requested(&mut self) {
+        let _ = self.signal.wait_for(|&requested| requested).await;
+    }
+}
 
 pub struct Server;
 
 impl Server {
-    pub async fn setup_server(addr: &str, pool: PgPool) -> io::Result<()> {
-        let listener = TcpListener::bind(addr).await?;
-        let users: Users = Arc::new(Mutex::new(HashMap::new()));
+    pub async fn setup_server(
+        config: Arc<Config>,
+        files: FileStore,
+        pool: PgPool,
+    ) -> io::Result<()> {
+        let listener = TcpListener::bind(&config.bind_addr).await?;
+        let state = ServerState::new(config.clone(), files, pool);
+
+        let (signal_tx, signal) = watch::channel(false);
+        let (done, mut all_done) = mpsc::channel(1);
+        let shutdown = Shutdown {
+            signal,
+            _done: done,
+        };
+
+        if let Some(addr) = &config.metrics_addr {
+            let addr = addr.parse().map_err(io::Error::other)?;
+            telemetry::install(addr, state.users.clone()).map_err(io::Error::other)?;
+            info!(%addr, "metrics endpoint listening");
+        }
+
+        if let Some(tls) = &config.tls {
+            let acceptor = tls.acceptor()?;
+            let tls_listener = TcpListener::bind(&tls.addr).await?;
+            info!(addr = %tls.addr, "TLS listener starting");
+
+            let state = state.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                let acceptor = Some(acceptor);
+                if let Err(e) = Self::accept_loop(tls_listener, acceptor, state, shutdown).await {
+                    error!(error = %e, "TLS listener stopped");
+                }
+            });
+        }
+
+        let timeout = config.shutdown_timeout;
+        let listener = tokio::spawn(Self::accept_loop(listener, None, state, shutdown));
+        tokio::pin!(listener);
+
+        tokio::select! {
+            result = &mut listener => result.map_err(io::Error::other)??,
+            _ = Self::shutdown_signal() => {}
+        }
+
+        info!(?timeout, "shutting down, waiting for connections");
+        let _ = signal_tx.send(true);
+        // Every task holds a `Shutdown`; recv returns None once all are gone.
+        if tokio::time::timeout(timeout, all_done.recv())
+            .await
+            .is_err()
+        {
+            warn!(?timeout, "connections still open after timeout, exiting");
+        }
+        Ok(())
+    }
+
+    /// Resolves on SIGINT, or SIGTERM on Unix.
+    async fn shutdown_signal() {
+        let interrupt = async {
+            if let Err(e) = tokio::signal::ctrl_c().await {
+                error!(error = %e, "failed to listen for SIGINT");
+                std::future::pending::<()>().await;
+            }
+        };
+
+        #[cfg(unix)]
+        let terminate = async {
+            use tokio::signal::unix::{SignalKind, signal};
+            match signal(SignalKind::terminate()) {
+                Ok(mut terminate) => {
+                    terminate.recv().await;
+                }
+                Err(e) => {
+                    error!(error = %e, "failed to listen for SIGTERM");
+                    std::future::pending::<()>().await;
+                }
+            }
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
 
+        tokio::select! {
+            _ = interrupt => {}
+            _ = terminate => {}
+        }
+    }
+
+    async fn accept_loop(
+        listener: TcpListener,
+        acceptor: Option<TlsAcceptor>,
+        state: ServerState,
+        mut shutdown: Shutdown,
+    ) -> io::Result<()> {
         loop {
-            match listener.accept().await {
-                Ok((stream, _)) => {
-                    let users = users.clone();
-                    let stream = stream;
-                    let pool = pool.clone();
-                    tokio::spawn(async move {
-                        if let Err(e) = Self::handle_client(stream, users, pool).await {
-                            eprintln!("ERROR: failed to handle client: {}", e);
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown.requested() => break Ok(()),
+            };
+            match accepted {
+                Ok((stream, peer)) => {
+                    if let Err(e) = Self::set_keepalive(&stream) {
+                        warn!(%peer, error = %e, "failed to enable TCP keepalive");
+                    }
+                    let span = info_span!(
+                        "conn",
+                        %peer,
+                        tls = acceptor.is_some(),
+                        user = field::Empty
+                    );
+                    let state = state.clone();
+                    let auth = Auth::new(state.user_db.clone(), peer.ip(), state.limiter.clone());
+                    let acceptor = acceptor.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(
+                        async move {
+                            let result = match acceptor {
+                                Some(acceptor) => match time::timeout(
+                                    state.config.auth_timeout,
+                                    acceptor.accept(stream),
+                                )
+                                .await
+                                .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into()))
+                                {
+                                    Ok(stream) => {
+                                        Self::handle_client(stream, auth, state, shutdown).await
+                                    }
+                                    Err(e) => Err(e),
+                                },
+                                None => Self::handle_client(stream, auth, state, shutdown).await,
+                            };
+                            if let Err(e) = result {
+                                warn!(error = %e, "connection failed");
+                            }
                         }
-                    });
+                        .instrument(span),
+                    );
                 }
                 Err(e) => {
-                    println!("ERROR: Couldn't get client: {:?}", e);
+                    error!(error = %e, "failed to accept connection");
                     break Ok(());
                 }
             }
         }
     }
 
-    async fn handle_client(stream: TcpStream, users: Users, pool: PgPool) -> io::Result<()> {
-        let (mut reader, mut writer) = stream.into_split();
-
-        let user_db = UserDb::new(pool);
-        let auth = Auth::new(user_db);
+    fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+        let keepalive = TcpKeepalive::new().with_time(KEEPALIVE_TIME);
+        SockRef::from(stream).set_tcp_keepalive(&keepalive)
+    }
 
-        let username = auth.auth(&mut writer, &mut reader).await;
-        let mut buf_reader = BufReader::new(&mut reader);
+    async fn handle_client<S>(
+        stream: S,
+        mut auth: Auth,
+        state: ServerState,
+        mut shutdown: Shutdown,
+    ) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        debug!("connected");
+        let (reader, writer) = io::split(stream);
+        let mut reader = FrameReader::new(Box::new(reader));
+        let mut writer = FrameWriter::new(Box::new(writer));
 
-        let username: String = username?;
-        let user = User::from_stream(writer, &buf_reader, &username).await?;
+        let config = state.config.clone();
 
-        {
-            let mut users_guard = users.lock().await;
-            users_guard.insert(user.username.clone(), user.clone());
+        let authenticated = time::timeout(config.auth_timeout, auth.auth(&mut writer, &mut reader));
+        let username = tokio::select! {
+            username = authenticated => match username {
+                Ok(username) => username?,
+                Err(_) => {
+                    info!("authentication timed out");
+                    let notice = "Authentication timed out.".to_string();
+                    let _ = writer.write_event(None, Event::Info { text: notice }).await;
+                    let _ = writer.shutdown().await;
+                    return Ok(());
+                }
+            },
+            _ = shutdown.requested() => {
+                let notice = "Server is shutting down.".to_string();
+                let _ = writer.write_event(None, Event::Info { text: notice }).await;
+                let _ = writer.shutdown().await;
+                return Ok(());
+            }
+        };
+        Span::current().record("user", username.as_str());
+        let role = state
+            .user_db
+            .find_role(&username)
+            .await
+            .map_err(io::Error::other)?
+            .unwrap_or(Role::User);
+        let (user, control) = User::from_stream(
+            writer,
+            &username,
+            role,
+            &config.default_channel,
+            config.ping_interval,
+            config.limits.outbound_queue,
+        )
+        .await?;
+        if let Some(motd) = &config.motd {
+            user.send(motd.clone()).await?;
         }
 
-        //println!("INFO: {} connected", user.username);
+        // One session per account: a new login replaces the older one.
+        let previous = state.users.insert(user.clone());
+        let ctx = Context::new(user.username.clone(), state.clone());
+        // However the session ends, it has to leave presence. Otherwise other
+        // users keep routing to it and a reconnect collides with it.
+        let result = Self::run_session(&ctx, &user, previous, &mut reader, control, shutdown).await;
 
-        let mut buffer = String::new();
+        state.users.remove_session(&user);
+        user.close();
+        user.closed().await;
 
-        while buf_reader.read_line(&mut buffer).await? > 0 {
-            let command = buffer.trim().to_string();
-            match CommandExecutor::execute(user.username.clone(), command, users.clone()).await {
-                Ok(ConnectionStatus::Continue) => {}
-                Ok(ConnectionStatus::Close) => break,
-                Err(e) => return Err(e),
-            }
-            buffer.clear();
-        }
+        info!("disconnected");
 
-        {
-            let mut users_guard = users.lock().await;
-            users_guard.remove(&user.username);
+        result
+    }
+
+    async fn run_session(
+        ctx: &Context,
+        user: &User,
+        previous: Option<User>,
+        reader: &mut FrameReader,
+        mut control: mpsc::UnboundedReceiver<CloseReason>,
+        mut shutdown: Shutdown,
+    ) -> io::Result<()> {
+        let state = &ctx.state;
+        let config = &state.config;
+        if let Some(previous) = previous {
+            let notice = "You have been signed in from another location.".to_string();
+            let _ = previous.send(notice).await;
+            previous.disconnect(CloseReason::Replaced);
+            user.send("Your previous session was closed.".to_string())
+                .await?;
         }
 
-        println!("INFO: {} disconnected", user.username);
+        CommandExecutor::deliver_offline_messages(ctx).await?;
+        CommandExecutor::send_channel_history(ctx, user.get_channel().to_string()).await?;
 
+        //println!("INFO: {} connected", user.username);
+
+        let idle = time::sleep(config.idle_timeout);
+        tokio::pin!(idle);
+        loop {
+            idle.as_mut().reset(Instant::now() + config.idle_timeout);
+            let input = tokio::select! {
+                input = reader.read_input() => match input {
+                    Ok(Some(input)) => input,
+                    Ok(None) => break,
+                    Err(e) if e.kind() == io::ErrorKind::InvalidInput => {
+                        user.send_event(Event::error("bad_request", e.to_string()))
+                            .await?;
+                        continue;
+                    }
+                    Err(e) => return Err(e),
+                },
+                Some(reason) = control.recv() => {
+                    info!(%reason, "closing connection");
+                    break;
+                }
+                _ = shutdown.requested() => {
+                    let _ = user.send("Server is shutting down.".to_string()).await;
+                    break;
+                }
+                _ = &mut idle => {
+                    info!(timeout = ?config.idle_timeout, "idle timeout");
+                    let notice = format!(
+                        "Disconnected after {} seconds without activity.",
+                        config.idle_timeout.as_secs()
+                    );
+                    let _ = user.send(notice).await;
+                    break;
+                }
+                // The writer task stops once a write fails.
+                _ = user.closed() => {
+                    info!("connection lost");
+                    break;
+                }
+            };
+            match input.text.as_str() {
+                PING => {
+                    user.reply(input.id, Event::Pong).await?;
+                    continue;
+                }
+                PONG => continue,
+                _ => {}
+            }
+            let status = CommandExecutor::execute(ctx, input.text).await?;
+            if let ConnectionStatus::ReceiveFile(upload) = &status {
+                let receive = time::timeout(
+                    config.idle_timeout,
+                    state.files.receive(reader, upload, user),
+                );
+                let received = tokio::select! {
+                    received = receive => received,
+                    _ = shutdown.requested() => {
+                        let _ = user.send("Server is shutting down.".to_string()).await;
+                        break;
+                    }
+                };
+                let Ok(outcome) = received else {
+                    info!(timeout = ?config.idle_timeout, "upload timed out");
+                    let notice = format!(
+                        "Upload not finished within {} seconds, disconnecting.",
+                        config.idle_timeout.as_secs()
+                    );
+                    let _ = user.send(notice).await;
+                    break;
+                };
+                let outcome = outcome?;
+                CommandExecutor::offer_file(ctx, upload.clone(), outcome).await?;
+            }
+            if input.id.is_some() {
+                let _ = user.reply(input.id, Event::Ack).await;
+            }
+            if let ConnectionStatus::Close = status {
+                break;
+            }
+        }
         Ok(())
     }
 }
 
-pub async fn start_server(addr: &str, pool: PgPool) -> io::Result<()> {
-    Server::setup_server(addr, pool).await
+pub async fn start_server(config: Arc<Config>, files: FileStore, pool: PgPool) -> io::Result<()> {
+    Server::setup_server(config, files, pool).await
 }
@@ -0,0 +1,61 @@
+use sqlx::PgPool;
+use std::sync::Arc;
+use tokio::io;
+
+use crate::auth::LoginLimiter;
+use crate::config::Config;
+use crate::db::{ChannelDb, MessageDb, UserDb};
+use crate::files::FileStore;
+use crate::flood::FloodControl;
+use crate::presence::Presence;
+use crate::protocol::Event;
+use crate::users::User;
+
+/// Online users and the channel each is in.
+pub type Users = Presence<User>;
+
+/// Everything connections share, built once at startup. Cloning is cheap:
+/// every field is a handle. Metrics are recorded through `telemetry`,
+/// which samples presence from `users`.
+#[derive(Clone)]
+pub struct ServerState {
+    pub config: Arc<Config>,
+    pub users: Users,
+    pub user_db: UserDb,
+    pub message_db: MessageDb,
+    pub channel_db: ChannelDb,
+    pub files: FileStore,
+    pub flood: FloodControl,
+    pub limiter: LoginLimiter,
+}
+
+impl ServerState {
+    pub fn new(config: Arc<Config>, files: FileStore, pool: PgPool) -> Self {
+        Self {
+            users: Users::new(),
+            user_db: UserDb::new(pool.clone()),
+            message_db: MessageDb::new(pool.clone()),
+            channel_db: ChannelDb::new(pool),
+            files,
+            flood: FloodControl::new(config.flood.clone()),
+            limiter: LoginLimiter::default(),
+            config,
+        }
+    }
+
+    /// Sends a line to `username`, if they are online.
+    pub async fn send_message(&self, username: &str, message: String) -> io::Result<()> {
+        match self.users.get(username) {
+            Some(user) => user.send(message).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Sends an event to `username`, if they are online.
+    pub async fn send_event(&self, username: &str, event: Event) -> io::Result<()> {
+        match self.users.get(username) {
+            Some(user) => user.send_event(event).await,
+            None => Ok(()),
+        }
+    }
+}
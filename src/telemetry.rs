@@ -0,0 +1,141 @@
+use metrics::{
+    Unit, counter, describe_counter, describe_gauge, describe_histogram, gauge, histogram,
+};
+use metrics_exporter_prometheus::{BuildError, Matcher, PrometheusBuilder};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use crate::state::Users;
+
+const CONNECTED_USERS: &str = "wur2_connected_users";
+const CHANNEL_USERS: &str = "wur2_channel_users";
+const MESSAGES: &str = "wur2_messages_total";
+const AUTH_ATTEMPTS: &str = "wur2_auth_attempts_total";
+const DB_QUERY_DURATION: &str = "wur2_db_query_duration_seconds";
+const FILE_TRANSFER_BYTES: &str = "wur2_file_transfer_bytes_total";
+const OUTBOUND_QUEUED: &str = "wur2_outbound_queued_messages";
+const OUTBOUND_MAX_DEPTH: &str = "wur2_outbound_queue_max_depth";
+const OUTBOUND_DROPPED: &str = "wur2_outbound_dropped_total";
+const SLOW_CONSUMERS: &str = "wur2_slow_consumer_disconnects_total";
+
+const PRESENCE_INTERVAL: Duration = Duration::from_secs(5);
+const QUERY_BUCKETS: &[f64] = &[
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Serves metrics in the Prometheus text format on `addr` and starts
+/// sampling presence and queue depths from `users`. Without this the
+/// recording functions below are no-ops.
+pub fn install(addr: SocketAddr, users: Users) -> Result<(), BuildError> {
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .set_buckets_for_metric(Matcher::Full(DB_QUERY_DURATION.to_string()), QUERY_BUCKETS)?
+        .install()?;
+
+    describe_gauge!(CONNECTED_USERS, "Users currently logged in.");
+    describe_gauge!(CHANNEL_USERS, "Users currently in each channel.");
+    describe_counter!(MESSAGES, "Chat messages sent, by kind.");
+    describe_counter!(AUTH_ATTEMPTS, "Login and registration attempts, by result.");
+    describe_histogram!(
+        DB_QUERY_DURATION,
+        Unit::Seconds,
+        "Latency of user database queries."
+    );
+    describe_counter!(
+        FILE_TRANSFER_BYTES,
+        Unit::Bytes,
+        "File bytes received from and sent to clients."
+    );
+    describe_gauge!(OUTBOUND_QUEUED, "Messages queued for all clients.");
+    describe_gauge!(
+        OUTBOUND_MAX_DEPTH,
+        "Messages queued for the slowest client."
+    );
+    describe_counter!(OUTBOUND_DROPPED, "Chat messages dropped from full queues.");
+    describe_counter!(
+        SLOW_CONSUMERS,
+        "Clients disconnected because their queue overflowed."
+    );
+
+    tokio::spawn(sample_presence(users));
+    Ok(())
+}
+
+async fn sample_presence(users: Users) {
+    let mut channels: HashSet<String> = HashSet::new();
+    let mut interval = tokio::time::interval(PRESENCE_INTERVAL);
+    loop {
+        interval.tick().await;
+        let per_channel = users.channel_sizes();
+        let online = users.all();
+        let mut queued = 0;
+        let mut max_depth = 0;
+        for user in &online {
+            let depth = user.queue_depth();
+            queued += depth;
+            max_depth = max_depth.max(depth);
+        }
+        let total = online.len();
+
+        gauge!(CONNECTED_USERS).set(total as f64);
+        gauge!(OUTBOUND_QUEUED).set(queued as f64);
+        gauge!(OUTBOUND_MAX_DEPTH).set(max_depth as f64);
+        // Channels that emptied since the last sample drop to zero.
+        for channel in &channels {
+            if !per_channel.contains_key(channel) {
+                gauge!(CHANNEL_USERS, "channel" => channel.clone()).set(0.0);
+            }
+        }
+        for (channel, count) in per_channel {
+            gauge!(CHANNEL_USERS, "channel" => channel.clone()).set(count as f64);
+            channels.insert(channel);
+        }
+    }
+}
+
+/// `kind` is `channel` or `direct`.
+pub fn message_sent(kind: &'static str) {
+    counter!(MESSAGES, "kind" => kind).increment(1);
+}
+
+/// `action` is `login` or `register`, `result` is `success`, `failure`
+/// or `error`.
+pub fn auth_attempt(action: &'static str, result: &'static str) {
+    counter!(AUTH_ATTEMPTS, "action" => action, "result" => result).increment(1);
+}
+
+/// `direction` is `upload` or `download`.
+pub fn file_bytes(direction: &'static str, bytes: u64) {
+    counter!(FILE_TRANSFER_BYTES, "direction" => direction).increment(bytes);
+}
+
+pub fn outbound_dropped() {
+    counter!(OUTBOUND_DROPPED).increment(1);
+}
+
+pub fn slow_consumer() {
+    counter!(SLOW_CONSUMERS).increment(1);
+}
+
+/// Records the time until it is dropped as the latency of `query`.
+pub struct QueryTimer {
+    query: &'static str,
+    start: Instant,
+}
+
+impl QueryTimer {
+    pub fn start(query: &'static str) -> Self {
+        Self {
+            query,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        histogram!(DB_QUERY_DURATION, "query" => self.query)
+            .record(self.start.elapsed().as_secs_f64());
+    }
+}
@@ -0,0 +1,50 @@
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub addr: String,
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl TlsConfig {
+    pub fn acceptor(&self) -> io::Result<TlsAcceptor> {
+        let certs = Self::load_certs(&self.cert_path)?;
+        let key = Self::load_key(&self.key_path)?;
+
+        let config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+
+    fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+        if certs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no certificates found in {}", path.display()),
+            ));
+        }
+        Ok(certs)
+    }
+
+    fn load_key(path: &PathBuf) -> io::Result<PrivateKeyDer<'static>> {
+        let mut reader = BufReader::new(File::open(path)?);
+        rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no private key found in {}", path.display()),
+            )
+        })
+    }
+}
@@ -1,89 +1,268 @@
-use tokio::io::{self, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use std::fmt;
+use std::time::Duration;
+use tokio::io::{self, AsyncReadExt};
 use tokio::sync::mpsc;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::warn;
+
+use crate::connection::FrameWriter;
+use crate::files::CHUNK_SIZE;
+use crate::models::Role;
+use crate::outbox::{self, MessageClass, Outbox, OutboxReceiver, PushError};
+use crate::presence::Member;
+use crate::protocol::{Event, Mode};
+use crate::telemetry;
+
+/// A write that takes longer than this means the client stopped reading.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(Debug)]
 pub enum UserMessage {
     Text(String),
     Binary(Vec<u8>),
+    Event {
+        id: Option<u64>,
+        event: Event,
+    },
+    /// Flushes everything queued before it and closes the socket.
+    Close,
+}
+
+impl UserMessage {
+    fn class(&self) -> MessageClass {
+        match self {
+            UserMessage::Event {
+                event: Event::Chat { .. } | Event::Direct { .. },
+                ..
+            } => MessageClass::Chat,
+            UserMessage::Binary(_)
+            | UserMessage::Event {
+                event: Event::FileChunk { .. },
+                ..
+            } => MessageClass::File,
+            _ => MessageClass::Control,
+        }
+    }
+}
+
+/// Why the server ended a connection, sent to the connection's reader loop.
+#[derive(Debug, Clone)]
+pub enum CloseReason {
+    Kicked {
+        by: String,
+    },
+    Replaced,
+    /// The outbound queue filled up with messages that could not be dropped.
+    SlowConsumer,
+}
+
+impl fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseReason::Kicked { by } => write!(f, "kicked by {by}"),
+            CloseReason::Replaced => write!(f, "replaced by a newer session"),
+            CloseReason::SlowConsumer => write!(f, "not reading fast enough"),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct User {
     pub username: String,
     pub channel: String,
-    pub role: String,
-    pub tx: mpsc::UnboundedSender<UserMessage>,
+    pub role: Role,
+    pub mode: Mode,
+    pub outbox: Outbox,
+    pub control: mpsc::UnboundedSender<CloseReason>,
 }
 
 impl User {
+    /// Also returns the receiving end of the control channel, which the
+    /// connection's reader loop watches for server-initiated disconnects.
     pub async fn from_stream(
-        writer: OwnedWriteHalf,
-        _reader: &BufReader<&mut OwnedReadHalf>,
-        username: &String,
-    ) -> io::Result<Self> {
-        let (tx, rx) = mpsc::unbounded_channel::<UserMessage>();
+        writer: FrameWriter,
+        username: &str,
+        role: Role,
+        channel: &str,
+        ping_interval: Duration,
+        queue_capacity: usize,
+    ) -> io::Result<(Self, mpsc::UnboundedReceiver<CloseReason>)> {
+        let (outbox, rx) = outbox::outbox(queue_capacity);
+        let (control, control_rx) = mpsc::unbounded_channel::<CloseReason>();
+        let mode = writer.mode();
 
-        tokio::spawn(Self::writer_task(writer, rx));
+        tokio::spawn(Self::writer_task(writer, rx, ping_interval));
 
-        let _ = tx.send(UserMessage::Text("=======================\n".to_string()));
-        let _ = tx.send(UserMessage::Text("||  Whats Up Rust 2  ||\n".to_string()));
-        let _ = tx.send(UserMessage::Text("=======================\n".to_string()));
+        for line in [
+            "=======================\n",
+            "||  Whats Up Rust 2  ||\n",
+            "=======================\n",
+        ] {
+            let _ = outbox.push(MessageClass::Control, UserMessage::Text(line.to_string()));
+        }
 
         //let username = format!("user_{}", rand::random::<u8>());
         let username = username.trim();
-        let channel = "Global".to_string();
-        let role = "User".to_string();
+        let channel = channel.to_string();
 
         let user = User {
             username: username.to_string(),
             channel,
             role,
-            tx,
+            mode,
+            outbox,
+            control,
         };
-        Ok(user)
+        Ok((user, control_rx))
     }
 
-    async fn writer_task(mut writer: OwnedWriteHalf, mut rx: mpsc::UnboundedReceiver<UserMessage>) {
-        while let Some(msg) = rx.recv().await {
-            let res = match msg {
-                UserMessage::Text(s) => writer.write_all(s.as_bytes()).await,
-                UserMessage::Binary(b) => writer.write_all(&b).await,
+    /// Writes queued messages until the socket fails or `Close` arrives.
+    /// Framed clients are also sent a `ping` every `ping_interval`, which
+    /// keeps a live client busy answering and surfaces dead sockets as
+    /// write errors.
+    async fn writer_task(mut writer: FrameWriter, mut rx: OutboxReceiver, ping_interval: Duration) {
+        let mut heartbeat = time::interval_at(Instant::now() + ping_interval, ping_interval);
+        heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let framed = writer.mode() == Mode::Framed;
+        loop {
+            let msg = tokio::select! {
+                msg = rx.recv() => match msg {
+                    Some(msg) => msg,
+                    None => break,
+                },
+                _ = heartbeat.tick(), if framed => UserMessage::Event {
+                    id: None,
+                    event: Event::Ping,
+                },
             };
-            if res.is_err() {
+            if let UserMessage::Close = msg {
+                let _ = time::timeout(WRITE_TIMEOUT, writer.shutdown()).await;
                 break;
             }
+            let write = async {
+                match msg {
+                    UserMessage::Text(s) => {
+                        let text = s.strip_suffix('\n').unwrap_or(&s).to_string();
+                        writer.write_event(None, Event::Info { text }).await
+                    }
+                    UserMessage::Binary(b) if writer.mode() == Mode::Text => {
+                        writer.write_raw(&b).await
+                    }
+                    UserMessage::Binary(_) | UserMessage::Close => Ok(()),
+                    UserMessage::Event { id, event } => writer.write_event(id, event).await,
+                }
+            };
+            match time::timeout(WRITE_TIMEOUT, write).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    warn!("write timed out, dropping connection");
+                    break;
+                }
+            }
         }
     }
 
     pub async fn send(&self, message: String) -> io::Result<()> {
-        self.tx
-            .send(UserMessage::Text(message + "\n"))
-            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Client disconnected"))
+        self.enqueue(UserMessage::Text(message + "\n")).await
     }
 
-    pub async fn send_file_stream(&self, mut file: tokio::fs::File) -> io::Result<()> {
-        let mut buffer = [0u8; 8192];
-        loop {
-            let n = file.read(&mut buffer).await?;
-            if n == 0 {
-                break;
+    pub async fn send_event(&self, event: Event) -> io::Result<()> {
+        self.reply(None, event).await
+    }
+
+    pub async fn reply(&self, id: Option<u64>, event: Event) -> io::Result<()> {
+        self.enqueue(UserMessage::Event { id, event }).await
+    }
+
+    /// Queues an event that must not be dropped, such as a stored message
+    /// that was already marked delivered. Like a file chunk, it waits for
+    /// the client to catch up instead of competing with live chat.
+    pub async fn send_paced(&self, event: Event) -> io::Result<()> {
+        match self
+            .outbox
+            .push_chunk(UserMessage::Event { id: None, event })
+            .await
+        {
+            Ok(()) => Ok(()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Client disconnected",
+            )),
+        }
+    }
+
+    /// Queues a message according to its class. A client that overflows
+    /// its queue is disconnected; the sender is not told, since it is
+    /// usually another user.
+    async fn enqueue(&self, message: UserMessage) -> io::Result<()> {
+        let result = match message.class() {
+            MessageClass::File => self.outbox.push_chunk(message).await,
+            class => self.outbox.push(class, message),
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(PushError::Closed) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "Client disconnected",
+            )),
+            Err(PushError::Overflow) => {
+                warn!(user = %self.username, "outbound queue full, disconnecting");
+                telemetry::slow_consumer();
+                self.disconnect(CloseReason::SlowConsumer);
+                Ok(())
             }
-            self.tx
-                .send(UserMessage::Binary(buffer[..n].to_vec()))
-                .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "ERROR: receiver closed"))?;
         }
-        Ok(())
     }
 
-    pub async fn switch_channel(&mut self, new_channel: String) -> io::Result<()> {
-        let old_channel = std::mem::replace(&mut self.channel, new_channel.clone());
-        let response = format!("Switched from {} to {}", old_channel, new_channel);
-        self.send(response).await
+    /// Asks the connection to close itself. Messages sent before this are
+    /// still delivered.
+    pub fn disconnect(&self, reason: CloseReason) {
+        let _ = self.control.send(reason);
+    }
+
+    /// Whether both handles belong to the same connection.
+    pub fn is_same_session(&self, other: &User) -> bool {
+        self.outbox.same_outbox(&other.outbox)
+    }
+
+    pub fn close(&self) {
+        self.outbox.close();
+    }
+
+    /// Resolves once the writer task has finished, i.e. everything queued
+    /// before `close` has been written out.
+    pub async fn closed(&self) {
+        self.outbox.closed().await
+    }
+
+    /// Messages waiting to be written to the client.
+    pub fn queue_depth(&self) -> usize {
+        self.outbox.depth()
     }
 
-    pub async fn change_role(&mut self) -> io::Result<()> {
-        let _ = std::mem::replace(&mut self.role, "Mod".to_string());
+    pub async fn send_file_stream(&self, id: i64, mut file: tokio::fs::File) -> io::Result<()> {
+        let mut buffer = [0u8; CHUNK_SIZE];
+        loop {
+            let n = file.read(&mut buffer).await?;
+            if n == 0 {
+                break;
+            }
+            let message = match self.mode {
+                Mode::Text => UserMessage::Binary(buffer[..n].to_vec()),
+                Mode::Framed => UserMessage::Event {
+                    id: None,
+                    event: Event::FileChunk {
+                        id,
+                        data: BASE64.encode(&buffer[..n]),
+                    },
+                },
+            };
+            self.enqueue(message).await?;
+            telemetry::file_bytes("download", n as u64);
+        }
         Ok(())
     }
 
@@ -98,3 +277,21 @@ impl User {
         )
     }
 }
+
+impl Member for User {
+    fn name(&self) -> &str {
+        &self.username
+    }
+
+    fn channel(&self) -> &str {
+        &self.channel
+    }
+
+    fn set_channel(&mut self, channel: String) {
+        self.channel = channel;
+    }
+
+    fn is_same_session(&self, other: &Self) -> bool {
+        User::is_same_session(self, other)
+    }
+}
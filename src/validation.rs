@@ -0,0 +1,149 @@
+use std::borrow::Cow;
+use validator::{Validate, ValidationError, ValidationErrors};
+
+pub const MIN_PASSWORD_LEN: u64 = 8;
+pub const MAX_PASSWORD_LEN: u64 = 128;
+
+#[derive(Debug, Validate)]
+pub struct Registration<'a> {
+    #[validate(
+        length(
+            min = 3,
+            max = 32,
+            message = "Usernames must be 3 to 32 characters long"
+        ),
+        custom = "name_characters"
+    )]
+    pub username: &'a str,
+    #[validate(length(
+        min = "MIN_PASSWORD_LEN",
+        max = "MAX_PASSWORD_LEN",
+        message = "Passwords must be 8 to 128 characters long"
+    ))]
+    pub password: &'a str,
+}
+
+/// Accounts created before registration was validated may not meet the
+/// current rules, so a login only has its password length capped.
+#[derive(Debug, Validate)]
+pub struct Login<'a> {
+    #[validate(length(
+        max = "MAX_PASSWORD_LEN",
+        message = "Passwords must be at most 128 characters long"
+    ))]
+    pub password: &'a str,
+}
+
+#[derive(Debug, Validate)]
+pub struct ChannelName<'a> {
+    #[validate(
+        length(
+            min = 1,
+            max = 32,
+            message = "Channel names must be 1 to 32 characters long"
+        ),
+        custom = "name_characters"
+    )]
+    pub name: &'a str,
+}
+
+/// Names end up in space- and comma-separated lists, so they are kept to
+/// ASCII letters, digits, `_`, `-` and `.`, starting with a letter or digit.
+fn name_characters(name: &str) -> Result<(), ValidationError> {
+    let starts_alphanumeric = name
+        .chars()
+        .next()
+        .is_none_or(|c| c.is_ascii_alphanumeric());
+    if starts_alphanumeric
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Ok(());
+    }
+    let mut error = ValidationError::new("characters");
+    error.message = Some(Cow::from(
+        "Names may only contain letters, digits, '_', '-' and '.', and must start with a letter or digit",
+    ));
+    Err(error)
+}
+
+/// Validates `input` and turns the first failed rule into a message that
+/// can be shown to the client.
+pub fn check(input: &impl Validate) -> Result<(), String> {
+    input.validate().map_err(|errors| describe(&errors))
+}
+
+fn describe(errors: &ValidationErrors) -> String {
+    let mut fields: Vec<_> = errors.field_errors().into_iter().collect();
+    // Field order is not preserved, so report them alphabetically.
+    fields.sort_by_key(|(field, _)| *field);
+    fields
+        .into_iter()
+        .flat_map(|(_, errors)| errors)
+        .find_map(|error| error.message.as_ref())
+        .map(|message| message.to_string())
+        .unwrap_or_else(|| "Invalid input".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn register(username: &str, password: &str) -> Result<(), String> {
+        check(&Registration { username, password })
+    }
+
+    #[test]
+    fn accepts_valid_registrations() {
+        assert_eq!(register("alice", "correct horse"), Ok(()));
+        assert_eq!(register("a_b-c.9", "12345678"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_usernames() {
+        let length = Err("Usernames must be 3 to 32 characters long".to_string());
+        assert_eq!(register("al", "12345678"), length);
+        assert_eq!(register(&"a".repeat(33), "12345678"), length);
+
+        for name in ["bob smith", "bob,eve", "_bob", "böb", ".bob"] {
+            let err = register(name, "12345678").unwrap_err();
+            assert!(err.starts_with("Names may only contain"), "{name}: {err}");
+        }
+    }
+
+    #[test]
+    fn checks_password_length() {
+        let err = register("alice", "short").unwrap_err();
+        assert_eq!(err, "Passwords must be 8 to 128 characters long");
+        assert!(register("alice", &"p".repeat(129)).is_err());
+
+        // Logins only cap the length.
+        assert_eq!(check(&Login { password: "short" }), Ok(()));
+        assert!(
+            check(&Login {
+                password: &"p".repeat(129)
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn reports_the_first_field_alphabetically() {
+        let err = register("a", "short").unwrap_err();
+        assert_eq!(err, "Passwords must be 8 to 128 characters long");
+    }
+
+    #[test]
+    fn checks_channel_names() {
+        assert_eq!(check(&ChannelName { name: "rust-lang" }), Ok(()));
+        assert!(check(&ChannelName { name: "" }).is_err());
+        assert!(check(&ChannelName { name: "#rust" }).is_err());
+        assert!(
+            check(&ChannelName {
+                name: &"c".repeat(33)
+            })
+            .is_err()
+        );
+    }
+}
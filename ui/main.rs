@@ -1,6 +1,4 @@
 use slint::{SharedString, VecModel};
-use std::io::{BufRead, BufReader, Write};
-use std::net::TcpStream;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc;
@@ -8,44 +6,78 @@ use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
+mod net;
+#[allow(dead_code)]
+#[path = "../src/protocol.rs"]
+mod protocol;
+
+use net::ConnectSettings;
+use protocol::{Event, Frame};
+
 slint::include_modules!();
 
 fn main() {
     let ui = wur2::new().unwrap();
 
-    let (tx_to_net, rx_from_ui) = mpsc::channel::<String>();
+    let (tx_to_net, mut rx_from_ui) = tokio::sync::mpsc::unbounded_channel::<String>();
     let (tx_to_ui, rx_from_net) = mpsc::channel::<NetEvent>();
 
     let is_authenticated = Arc::new(AtomicBool::new(false));
     let is_authenticated_net = is_authenticated.clone();
 
     thread::spawn(move || {
-        let mut stream = TcpStream::connect("127.0.0.1:6969").expect("failed to connect");
-
-        let mut reader = BufReader::new(stream.try_clone().unwrap());
-
-        let tx_ui = tx_to_ui.clone();
-        thread::spawn(move || {
-            let mut line = String::new();
-            loop {
-                line.clear();
-                if reader.read_line(&mut line).is_ok() {
-                    let msg = line.trim().to_string();
-                    if msg.starts_with("Welcome") {
-                        is_authenticated_net.store(true, Ordering::Relaxed);
-                    }
-                    if let Some(list) = parse_user_list(&msg) {
-                        let _ = tx_ui.send(NetEvent::UserList(list));
-                    } else if !msg.is_empty() {
-                        let _ = tx_ui.send(NetEvent::Chat(msg));
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start network runtime");
+
+        runtime.block_on(async move {
+            let settings = ConnectSettings::from_env();
+            let (mut reader, mut writer) =
+                net::connect(&settings).await.expect("failed to connect");
+
+            let tx_ui = tx_to_ui.clone();
+            let (tx_pong, mut rx_pong) = tokio::sync::mpsc::unbounded_channel::<()>();
+            tokio::spawn(async move {
+                while let Ok(frame) = net::read_frame(&mut reader).await {
+                    match frame.event {
+                        Event::Ping => {
+                            let _ = tx_pong.send(());
+                        }
+                        Event::Presence { users } => {
+                            let _ = tx_ui.send(NetEvent::UserList(users));
+                        }
+                        Event::AuthResult { ok, message, .. } => {
+                            is_authenticated_net.store(ok, Ordering::Relaxed);
+                            let _ = tx_ui.send(NetEvent::Auth { ok, message });
+                        }
+                        Event::Ack | Event::Pong | Event::Prompt { .. } => {}
+                        event => {
+                            if let Some(msg) = event.to_text()
+                                && !msg.trim().is_empty()
+                            {
+                                let _ = tx_ui.send(NetEvent::Chat(msg.trim().to_string()));
+                            }
+                        }
                     }
                 }
+            });
+
+            let mut next_id = 0u64;
+            loop {
+                let frame = tokio::select! {
+                    msg = rx_from_ui.recv() => match msg {
+                        Some(msg) => {
+                            next_id += 1;
+                            Frame::new(Some(next_id), Event::Command { text: msg })
+                        }
+                        None => break,
+                    },
+                    Some(()) = rx_pong.recv() => Frame::new(None, Event::Pong),
+                };
+                let _ = net::write_frame(&mut writer, &frame).await;
             }
         });
-
-        for msg in rx_from_ui {
-            let _ = writeln!(stream, "{msg}");
-        }
     });
 
     let tx_to_net_list = tx_to_net.clone();
@@ -62,6 +94,16 @@ fn main() {
 
     ui.set_online_users(Rc::new(VecModel::<SharedString>::from(vec![])).into());
 
+    let tx_to_net_login = tx_to_net.clone();
+    ui.on_login(move |username, password| {
+        let _ = tx_to_net_login.send(format!("LOGIN {username} {password}"));
+    });
+
+    let tx_to_net_register = tx_to_net.clone();
+    ui.on_register(move |username, password| {
+        let _ = tx_to_net_register.send(format!("REGISTER {username} {password}"));
+    });
+
     let history_handle = history.clone();
     ui.on_add_to_history(move |text| {
         history_handle.push(text.clone().into());
@@ -89,6 +131,15 @@ fn main() {
                         NetEvent::Chat(msg) => {
                             ui.invoke_append_message(msg.into());
                         }
+                        NetEvent::Auth { ok, message } => {
+                            ui.set_authenticated(ok);
+                            ui.set_login_password(SharedString::new());
+                            if ok {
+                                ui.invoke_append_message(message.into());
+                            } else {
+                                ui.set_auth_error(message.into());
+                            }
+                        }
                         NetEvent::UserList(users) => {
                             let model = Rc::new(VecModel::<SharedString>::from(
                                 users
@@ -110,27 +161,6 @@ fn main() {
 
 enum NetEvent {
     Chat(String),
+    Auth { ok: bool, message: String },
     UserList(Vec<String>),
 }
-
-fn parse_user_list(msg: &str) -> Option<Vec<String>> {
-    let prefix = "Connected users:";
-    let trimmed = msg.trim();
-    if !trimmed.starts_with(prefix) {
-        return None;
-    }
-
-    let list = trimmed[prefix.len()..].trim();
-    if list.is_empty() {
-        return Some(Vec::new());
-    }
-
-    let users = list
-        .split(',')
-        .map(|name| name.trim())
-        .filter(|name| !name.is_empty())
-        .map(|name| name.to_string())
-        .collect::<Vec<_>>();
-
-    Some(users)
-}
@@ -0,0 +1,218 @@
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader as StdBufReader};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, ReadHalf,
+    WriteHalf,
+};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::client::danger::{
+    HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier,
+};
+use tokio_rustls::rustls::crypto::{self, CryptoProvider};
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{
+    self, CertificateError, ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
+
+use crate::protocol::{Frame, HELLO, HELLO_OK, MAX_FRAME_LEN};
+
+pub trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Stream for T {}
+
+pub type Reader = BufReader<ReadHalf<Box<dyn Stream>>>;
+pub type Writer = WriteHalf<Box<dyn Stream>>;
+
+#[derive(Debug, Clone)]
+pub enum TrustMode {
+    /// Trust certificates signed by any CA in the given PEM bundle.
+    Ca(PathBuf),
+    /// Trust only the exact server certificate stored in the given PEM file.
+    Pinned(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct TlsSettings {
+    pub server_name: String,
+    pub trust: TrustMode,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectSettings {
+    pub addr: String,
+    pub tls: Option<TlsSettings>,
+}
+
+impl ConnectSettings {
+    /// Reads `WUR2_SERVER`, `WUR2_TLS_NAME`, `WUR2_CA_CERT` and
+    /// `WUR2_PINNED_CERT`. TLS is used as soon as a trust source is set.
+    pub fn from_env() -> Self {
+        let addr = env::var("WUR2_SERVER").unwrap_or("127.0.0.1:6969".to_string());
+        let server_name = env::var("WUR2_TLS_NAME").unwrap_or("localhost".to_string());
+
+        let trust = match (env::var("WUR2_PINNED_CERT"), env::var("WUR2_CA_CERT")) {
+            (Ok(pinned), _) => Some(TrustMode::Pinned(pinned.into())),
+            (_, Ok(ca)) => Some(TrustMode::Ca(ca.into())),
+            _ => None,
+        };
+
+        Self {
+            addr,
+            tls: trust.map(|trust| TlsSettings { server_name, trust }),
+        }
+    }
+}
+
+pub async fn connect(settings: &ConnectSettings) -> io::Result<(Reader, Writer)> {
+    let tcp = TcpStream::connect(&settings.addr).await?;
+
+    let stream: Box<dyn Stream> = match &settings.tls {
+        Some(tls) => {
+            let connector = TlsConnector::from(Arc::new(client_config(&tls.trust)?));
+            let server_name = ServerName::try_from(tls.server_name.clone())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            Box::new(connector.connect(server_name, tcp).await?)
+        }
+        None => Box::new(tcp),
+    };
+
+    let (reader, mut writer) = tokio::io::split(stream);
+    let mut reader = BufReader::new(reader);
+    negotiate(&mut reader, &mut writer).await?;
+    Ok((reader, writer))
+}
+
+async fn negotiate(reader: &mut Reader, writer: &mut Writer) -> io::Result<()> {
+    writer.write_all(format!("{HELLO}\n").as_bytes()).await?;
+    writer.flush().await?;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line.trim() == HELLO_OK {
+            return Ok(());
+        }
+    }
+}
+
+pub async fn read_frame(reader: &mut Reader) -> io::Result<Frame> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::ErrorKind::InvalidData.into());
+    }
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+    Frame::decode(&body)
+}
+
+pub async fn write_frame(writer: &mut Writer, frame: &Frame) -> io::Result<()> {
+    writer.write_all(&frame.encode()?).await?;
+    writer.flush().await
+}
+
+fn client_config(trust: &TrustMode) -> io::Result<ClientConfig> {
+    let config = match trust {
+        TrustMode::Ca(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(path)? {
+                roots
+                    .add(cert)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            }
+            ClientConfig::builder()
+                .with_root_certificates(roots)
+                .with_no_client_auth()
+        }
+        TrustMode::Pinned(path) => {
+            let pinned = load_certs(path)?.remove(0);
+            let verifier = PinnedCertVerifier {
+                pinned,
+                provider: Arc::new(crypto::ring::default_provider()),
+            };
+            ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        }
+    };
+    Ok(config)
+}
+
+fn load_certs(path: &PathBuf) -> io::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = StdBufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("no certificates found in {}", path.display()),
+        ));
+    }
+    Ok(certs)
+}
+
+/// Accepts exactly one server certificate, which lets users connect to a
+/// server with a self-signed certificate without trusting it as a CA.
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    pinned: CertificateDer<'static>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if end_entity.as_ref() == self.pinned.as_ref() {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}